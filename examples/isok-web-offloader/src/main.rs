@@ -32,7 +32,10 @@ async fn run_broker(kafka_boostrap_servers: String) -> JoinHandle<Result<(), Str
                 std::net::Ipv4Addr::LOCALHOST.into(),
                 BROKER_PORT,
             ),
+            tls: None,
         },
+        auth: None,
+        checks: None,
     };
     tokio::spawn(async move {
         isok_broker::run(broker_config)
@@ -58,6 +61,10 @@ async fn run_agent(broker_endpoint: String) -> JoinHandle<Result<(), String>> {
             region: "localhost".to_string(),
             batch: 100,
             batch_interval: 10,
+            health_check_interval: 10,
+            spool: None,
+            token: None,
+            tls: None,
         }),
         check_config_adapter: isok_agent::config::ConfigCheckAdapter::Static(
             isok_agent::config::StaticConfigAdapter {
@@ -80,6 +87,7 @@ async fn run_agent(broker_endpoint: String) -> JoinHandle<Result<(), String>> {
                 ],
             },
         ),
+        scheduler: Default::default(),
     };
     tokio::spawn(async move {
         isok_agent::run(agent_config)
@@ -1,10 +1,18 @@
-use isok_agent::config::{Config as AgentConfig, ConfigCheckAdapter, ResultSenderAdapter};
+use isok_agent::config::{
+    BrokerConfig as AgentBrokerConfig, BrokerConfigCheckAdapter, BrokerTlsConfig,
+    Config as AgentConfig, ConfigCheckAdapter, ResultSenderAdapter,
+};
 use isok_agent::jobs::Job;
-use isok_broker::config::{ApiConfig, Config as BrokerConfig, Error as BrokerError, KafkaConfig};
+use isok_broker::config::{
+    ApiConfig, ApiTlsConfig, ChecksConfig, Config as BrokerConfig, Error as BrokerError,
+    KafkaConfig,
+};
 use isok_broker::run;
 use once_cell::sync::Lazy;
 use prost::Message;
+use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
 use rdkafka::consumer::{Consumer, StreamConsumer};
+use rdkafka::message::OwnedMessage;
 use rdkafka::mocking::MockCluster;
 use rdkafka::producer::DefaultProducerContext;
 use rdkafka::{ClientConfig, ClientContext};
@@ -12,10 +20,13 @@ use std::collections::HashMap;
 use std::net::{Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
 use std::sync::atomic::AtomicUsize;
+use std::time::Duration;
+use tempfile::TempDir;
 use tokio::io::AsyncReadExt;
-use tokio::net::UnixListener;
+use tokio::net::{TcpStream, UnixListener};
 use tokio::sync::mpsc::UnboundedReceiver;
 use tokio::task::JoinHandle;
+use tokio::time::Instant;
 use ulid::Ulid;
 
 pub static NEXT_PORT: AtomicUsize = AtomicUsize::new(24000);
@@ -35,7 +46,17 @@ pub struct BrokerTestingRunner<'c, T: ClientContext> {
     pub listening_port: u16,
 }
 
+impl Default for BrokerTestingRunner<'static, DefaultProducerContext> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl BrokerTestingRunner<'static, DefaultProducerContext> {
+    const POLL_INTERVAL: Duration = Duration::from_millis(200);
+    /// Maximum duration a consumer waits for a message before being subscribed again
+    const RECV_ATTEMPT: Duration = Duration::from_secs(5);
+
     pub fn new() -> Self {
         let mock_cluster = MockCluster::new(1).expect("Failed to create mock cluster");
         mock_cluster
@@ -57,8 +78,11 @@ impl BrokerTestingRunner<'static, DefaultProducerContext> {
         let config = BrokerConfig {
             kafka: kafka_config,
             api: ApiConfig {
-                listen_address: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port.clone()),
+                listen_address: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port),
+                tls: None,
             },
+            auth: None,
+            checks: None,
         };
         Self {
             config,
@@ -72,17 +96,70 @@ impl BrokerTestingRunner<'static, DefaultProducerContext> {
         format!("test-{}", Ulid::new().to_string())
     }
 
+    pub fn with_tls(mut self, tls: ApiTlsConfig) -> Self {
+        self.config.api.tls = Some(tls);
+        self
+    }
+
+    pub fn with_checks(mut self, path: PathBuf) -> Self {
+        self.config.checks = Some(ChecksConfig { path });
+        self
+    }
+
     pub fn start_broker(mut self) -> Self {
         let config = self.config.clone();
         self.broker_handle = Some(tokio::spawn(async move { run(config).await }));
         self
     }
 
+    /// Wait for the broker to accept connections, panicking after `timeout`
+    pub async fn wait_until_listening(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        while TcpStream::connect((Ipv4Addr::LOCALHOST, self.listening_port))
+            .await
+            .is_err()
+        {
+            assert!(
+                Instant::now() < deadline,
+                "Expected the broker to listen before the timeout"
+            );
+            tokio::time::sleep(Self::POLL_INTERVAL).await;
+        }
+    }
+
+    /// Wait for a message produced by the broker, panicking after `timeout`. The topic
+    /// only exists once the broker produced a first message, a new consumer is
+    /// subscribed whenever consuming fails until a message is received.
+    pub async fn recv_message(&self, timeout: Duration) -> OwnedMessage {
+        let deadline = Instant::now() + timeout;
+        let mut consumer = self.get_topic_consumer();
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            assert!(
+                !remaining.is_zero(),
+                "Expected a message before the timeout"
+            );
+            // The consumer is kept while it waits, joining the group again on every
+            // attempt could keep it from ever being assigned the topic
+            match tokio::time::timeout(remaining.min(Self::RECV_ATTEMPT), consumer.recv()).await {
+                Ok(Ok(message)) => return message.detach(),
+                Ok(Err(e)) => {
+                    tracing::debug!("TESTS - Unable to consume message yet: {}", e);
+                    tokio::time::sleep(Self::POLL_INTERVAL).await;
+                    consumer = self.get_topic_consumer();
+                }
+                Err(_) => tracing::debug!("TESTS - No message received yet"),
+            }
+        }
+    }
+
     pub fn get_topic_consumer(&self) -> StreamConsumer {
         let consumer: StreamConsumer = ClientConfig::new()
             .set("bootstrap.servers", self.mock_cluster.bootstrap_servers())
             .set("group.id", "test_kafka_message_integrity")
             .set("fetch.wait.max.ms", "1000")
+            // Messages produced before the consumer is assigned the topic are received
+            .set("auto.offset.reset", "earliest")
             .create()
             .expect("Consumer creation failed");
 
@@ -97,6 +174,12 @@ pub struct AgentTestingRunner {
     config: AgentConfig,
 }
 
+impl Default for AgentTestingRunner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl AgentTestingRunner {
     pub fn new() -> Self {
         Self {
@@ -117,6 +200,16 @@ impl AgentTestingRunner {
         self
     }
 
+    pub fn use_broker_sender(mut self, config: AgentBrokerConfig) -> Self {
+        self.config.result_sender_adapter = ResultSenderAdapter::Broker(config);
+        self
+    }
+
+    pub fn use_broker_checks(mut self, config: BrokerConfigCheckAdapter) -> Self {
+        self.config.check_config_adapter = ConfigCheckAdapter::Broker(config);
+        self
+    }
+
     pub fn create_path_socket_listener<T: Message + Default + 'static>(
         path: PathBuf,
     ) -> (UnboundedReceiver<T>, JoinHandle<()>) {
@@ -172,3 +265,74 @@ impl AgentTestingRunner {
         tokio::spawn(self.start_threads())
     }
 }
+
+/// Certificates generated at test time, a self-signed CA signs both the broker
+/// certificate (for `localhost`) and the agent client certificate
+pub struct TestPki {
+    _dir: TempDir,
+    pub ca_cert: PathBuf,
+    pub broker_cert: PathBuf,
+    pub broker_key: PathBuf,
+    pub agent_cert: PathBuf,
+    pub agent_key: PathBuf,
+}
+
+impl Default for TestPki {
+    fn default() -> Self {
+        Self::generate()
+    }
+}
+
+impl TestPki {
+    pub fn generate() -> Self {
+        let dir = tempfile::tempdir().expect("Failed to create PKI directory");
+        let write = |name: &str, content: String| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, content).expect("Failed to write PKI file");
+            path
+        };
+
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(vec![]).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+
+        let broker_key = KeyPair::generate().unwrap();
+        let broker = CertificateParams::new(vec!["localhost".to_string()])
+            .unwrap()
+            .signed_by(&broker_key, &ca, &ca_key)
+            .unwrap();
+
+        let agent_key = KeyPair::generate().unwrap();
+        let agent = CertificateParams::new(vec!["isok-agent".to_string()])
+            .unwrap()
+            .signed_by(&agent_key, &ca, &ca_key)
+            .unwrap();
+
+        Self {
+            ca_cert: write("ca.pem", ca.pem()),
+            broker_cert: write("broker.pem", broker.pem()),
+            broker_key: write("broker.key", broker_key.serialize_pem()),
+            agent_cert: write("agent.pem", agent.pem()),
+            agent_key: write("agent.key", agent_key.serialize_pem()),
+            _dir: dir,
+        }
+    }
+
+    pub fn broker_tls_config(&self, mutual: bool) -> ApiTlsConfig {
+        ApiTlsConfig {
+            cert: self.broker_cert.clone(),
+            key: self.broker_key.clone(),
+            client_ca: mutual.then(|| self.ca_cert.clone()),
+        }
+    }
+
+    pub fn agent_tls_config(&self, with_identity: bool) -> BrokerTlsConfig {
+        BrokerTlsConfig {
+            ca_cert: Some(self.ca_cert.clone()),
+            client_cert: with_identity.then(|| self.agent_cert.clone()),
+            client_key: with_identity.then(|| self.agent_key.clone()),
+            domain_name: None,
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use integration_tests::{AgentTestingRunner, BrokerTestingRunner, TRACING};
+use isok_agent::config::{BrokerConfig as AgentBrokerConfig, BrokerConfigCheckAdapter};
+use isok_data::broker_rpc::broker_client::BrokerClient;
+use isok_data::broker_rpc::{CheckResult, GetChecksRequest, Tags};
+use once_cell::sync::Lazy;
+use pretty_assertions::assert_eq;
+use prost::Message;
+use rdkafka::Message as KafkaMessage;
+use std::time::Duration;
+
+const CHECK_ID: &str = "01JGZ3NDEKTSV4RRWETS2EGZ5M";
+
+fn write_checks(dir: &tempfile::TempDir, broker_port: u16) -> std::path::PathBuf {
+    let path = dir.path().join("checks.yaml");
+    std::fs::write(
+        &path,
+        format!(
+            r#"
+            check_sets:
+              - zones: ["dev"]
+                checks:
+                  - id: "{CHECK_ID}"
+                    type: "tcp"
+                    pretty_name: "broker"
+                    endpoint: "127.0.0.1:{broker_port}"
+                    secured: false
+                    interval: 1
+            "#
+        ),
+    )
+    .unwrap();
+    path
+}
+
+fn tags(zone: &str) -> Tags {
+    Tags {
+        agent_id: "test".to_string(),
+        zone: zone.to_string(),
+        region: "localhost".to_string(),
+    }
+}
+
+// The broker only sends the checks of an agent again once they changed
+#[tokio::test]
+async fn test_get_checks() {
+    Lazy::force(&TRACING);
+    let dir = tempfile::tempdir().unwrap();
+    let broker = BrokerTestingRunner::new();
+    let path = write_checks(&dir, broker.listening_port);
+    let broker = broker.with_checks(path).start_broker();
+    broker.wait_until_listening(Duration::from_secs(30)).await;
+
+    let mut client = BrokerClient::connect(format!("http://localhost:{}", broker.listening_port))
+        .await
+        .expect("Failed to connect to broker");
+    let response = client
+        .get_checks(GetChecksRequest {
+            tags: Some(tags("dev")),
+            version: None,
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(!response.unchanged);
+    assert_eq!(response.checks.len(), 1);
+
+    let unchanged = client
+        .get_checks(GetChecksRequest {
+            tags: Some(tags("dev")),
+            version: Some(response.version.clone()),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(unchanged.unchanged);
+    assert!(unchanged.checks.is_empty());
+
+    // Agents of other zones don't run the check
+    let other_zone = client
+        .get_checks(GetChecksRequest {
+            tags: Some(tags("prod")),
+            version: Some(response.version),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(!other_zone.unchanged);
+    assert!(other_zone.checks.is_empty());
+}
+
+// An agent runs the checks the broker serves, and reports them under their ID
+#[tokio::test]
+async fn test_agent_pulls_checks_from_broker() {
+    Lazy::force(&TRACING);
+    let dir = tempfile::tempdir().unwrap();
+    let broker = BrokerTestingRunner::new();
+    let path = write_checks(&dir, broker.listening_port);
+    let broker = broker.with_checks(path).start_broker();
+    broker.wait_until_listening(Duration::from_secs(30)).await;
+
+    let address = format!("http://localhost:{}", broker.listening_port);
+    let agent = AgentTestingRunner::new()
+        .use_broker_checks(BrokerConfigCheckAdapter {
+            main_broker: address.clone(),
+            fallback_brokers: vec![],
+            agent_id: "test".to_string(),
+            zone: "dev".to_string(),
+            region: "localhost".to_string(),
+            poll_interval: 30,
+            token: None,
+            tls: None,
+        })
+        .use_broker_sender(AgentBrokerConfig {
+            main_broker: address,
+            fallback_brokers: vec![],
+            agent_id: "test".to_string(),
+            zone: "dev".to_string(),
+            region: "localhost".to_string(),
+            batch: 1,
+            batch_interval: 0,
+            health_check_interval: 10,
+            spool: None,
+            token: None,
+            tls: None,
+        })
+        .run();
+
+    let msg = broker.recv_message(Duration::from_secs(60)).await;
+    agent.abort();
+
+    let result = CheckResult::decode(msg.payload().unwrap()).expect("Expected to decode message");
+    assert_eq!(result.id_ulid, CHECK_ID);
+}
@@ -32,6 +32,8 @@ async fn test_kafka_message_integrity() {
                     status: isok_data::broker_rpc::CheckJobStatus::Reachable as i32,
                     metrics: Default::default(),
                     tags: None,
+                    reason: None,
+                    received_at: None,
                     details: Default::default(),
                 }],
                 created_at: None,
@@ -53,4 +55,5 @@ async fn test_kafka_message_integrity() {
         isok_data::broker_rpc::CheckJobStatus::Reachable as i32
     );
     assert_eq!(result.id_ulid, "test");
+    assert!(result.received_at.is_some());
 }
@@ -1 +1,3 @@
+mod checks;
 mod kafka;
+mod tls;
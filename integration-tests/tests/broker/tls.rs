@@ -0,0 +1,85 @@
+use integration_tests::{AgentTestingRunner, BrokerTestingRunner, TestPki, TRACING};
+use isok_agent::config::BrokerConfig as AgentBrokerConfig;
+use isok_agent::jobs::tcp::TcpJob;
+use isok_agent::jobs::{Job, JobInnerConfig};
+use isok_data::broker_rpc::broker_client::BrokerClient;
+use isok_data::broker_rpc::{CheckResult, HealthRequest};
+use once_cell::sync::Lazy;
+use pretty_assertions::assert_eq;
+use prost::Message;
+use rdkafka::Message as KafkaMessage;
+use std::time::Duration;
+use tonic::transport::{Certificate, ClientTlsConfig, Endpoint, Identity};
+
+// An agent presenting a certificate signed by the broker client CA delivers its results
+#[tokio::test]
+async fn test_agent_to_broker_mutual_tls() {
+    Lazy::force(&TRACING);
+    let pki = TestPki::generate();
+    let broker = BrokerTestingRunner::new()
+        .with_tls(pki.broker_tls_config(true))
+        .start_broker();
+
+    let tcp_config =
+        JobInnerConfig::Tcp(TcpJob::new(format!("127.0.0.1:{}", broker.listening_port)));
+    let check = Job::new(Duration::from_secs(1), tcp_config, "broker".to_string());
+    let expected_id = check.id().to_string();
+    let agent = AgentTestingRunner::new()
+        .add_check(check)
+        .use_broker_sender(AgentBrokerConfig {
+            main_broker: format!("https://localhost:{}", broker.listening_port),
+            fallback_brokers: vec![],
+            agent_id: "test".to_string(),
+            zone: "dev".to_string(),
+            region: "localhost".to_string(),
+            batch: 1,
+            batch_interval: 0,
+            health_check_interval: 10,
+            spool: None,
+            token: None,
+            tls: Some(pki.agent_tls_config(true)),
+        })
+        .run();
+
+    let msg = broker.recv_message(Duration::from_secs(60)).await;
+    agent.abort();
+
+    let result = CheckResult::decode(msg.payload().unwrap()).expect("Expected to decode message");
+    assert_eq!(result.id_ulid, expected_id);
+}
+
+// The broker refuses agents that don't present a client certificate
+#[tokio::test]
+async fn test_broker_rejects_agent_without_certificate() {
+    Lazy::force(&TRACING);
+    let pki = TestPki::generate();
+    let broker = BrokerTestingRunner::new()
+        .with_tls(pki.broker_tls_config(true))
+        .start_broker();
+    broker.wait_until_listening(Duration::from_secs(30)).await;
+
+    let endpoint =
+        Endpoint::from_shared(format!("https://localhost:{}", broker.listening_port)).unwrap();
+    let ca_cert = Certificate::from_pem(std::fs::read(&pki.ca_cert).unwrap());
+    let tls = ClientTlsConfig::new().ca_certificate(ca_cert);
+
+    let channel = endpoint
+        .clone()
+        .tls_config(tls.clone())
+        .unwrap()
+        .connect_lazy();
+    let response = BrokerClient::new(channel).health(HealthRequest {}).await;
+    assert!(response.is_err());
+
+    // The same client is accepted once it presents its certificate
+    let identity = Identity::from_pem(
+        std::fs::read(&pki.agent_cert).unwrap(),
+        std::fs::read(&pki.agent_key).unwrap(),
+    );
+    let channel = endpoint
+        .tls_config(tls.identity(identity))
+        .unwrap()
+        .connect_lazy();
+    let response = BrokerClient::new(channel).health(HealthRequest {}).await;
+    assert!(response.is_ok());
+}
@@ -1,23 +1,36 @@
-use crate::config::{BrokerConfig, ResultSenderAdapter, SocketConfig};
+use crate::batch_sender::spool::Spool;
+use crate::config::{BrokerConfig, BrokerTlsConfig, ResultSenderAdapter, SocketConfig};
+use crate::jobs::http::timing::HttpTimings;
 use enum_dispatch::enum_dispatch;
+use futures::future::join_all;
 use isok_data::broker_rpc::broker_client::BrokerClient;
 use isok_data::broker_rpc::check_result::Details;
-use isok_data::broker_rpc::{BrokerGrpcClient, CheckJobMetrics, CheckJobStatus, CheckResult, Tags};
+use isok_data::broker_rpc::{
+    BrokerGrpcClient, CheckBatchRequest, CheckJobMetrics, CheckJobStatus, CheckResult, Tags,
+};
 use isok_data::JobId;
 use prost::Message;
-use std::time::Duration;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
 use tokio::io::AsyncWriteExt;
 use tokio::net::UnixStream;
 use tokio::sync::mpsc::UnboundedReceiver;
-use tokio::time::Instant;
-use tonic::transport::Channel;
+use tokio::time::{Instant, MissedTickBehavior};
+use tonic::metadata::{Ascii, MetadataValue};
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint, Identity};
+
+mod spool;
 
 #[derive(Debug)]
 pub struct JobResult {
     pub id: JobId,
-    pub run_at: Instant,
+    /// Wall-clock time at which the execution started
+    pub run_at: SystemTime,
     pub status: CheckJobStatus,
     pub latency: Option<Duration>,
+    /// Latency breakdown, only set by HTTP checks
+    pub timings: Option<HttpTimings>,
+    pub reason: Option<String>,
     pub details: Option<Details>,
 }
 
@@ -25,10 +38,12 @@ impl JobResult {
     pub fn new(id: JobId) -> Self {
         JobResult {
             id,
-            run_at: Instant::now(),
+            run_at: SystemTime::now(),
             status: CheckJobStatus::Unknown,
             details: None,
             latency: None,
+            timings: None,
+            reason: None,
         }
     }
 
@@ -39,39 +54,92 @@ impl JobResult {
     pub(crate) fn set_latency(&mut self, latency: Duration) {
         self.latency = Some(latency);
     }
+
+    pub(crate) fn set_timings(&mut self, timings: HttpTimings) {
+        self.timings = Some(timings);
+    }
+
+    pub(crate) fn set_reason(&mut self, reason: impl Into<String>) {
+        self.reason = Some(reason.into());
+    }
+
+    pub(crate) fn set_details(&mut self, details: Details) {
+        self.details = Some(details);
+    }
 }
 
 pub struct BatchSender {
     connector: BatchSenderType,
     rx: UnboundedReceiver<JobResult>,
+    health_check_interval: Duration,
+    flush_interval: Duration,
 }
 
 impl BatchSender {
+    const DEFAULT_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+    const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
     pub async fn new(
         adapter_cfg: ResultSenderAdapter,
         rx: UnboundedReceiver<JobResult>,
     ) -> Result<Self, BatchSenderError> {
+        let mut health_check_interval = Self::DEFAULT_HEALTH_CHECK_INTERVAL;
+        let mut flush_interval = Self::DEFAULT_FLUSH_INTERVAL;
         let connector = match adapter_cfg {
             ResultSenderAdapter::Stdout => BatchSenderType::Stdout(StdoutBatchSender::new()),
             ResultSenderAdapter::Broker(config) => {
+                health_check_interval = Duration::from_secs(config.health_check_interval.max(1));
+                flush_interval = Duration::from_secs(config.batch_interval.max(1));
                 BatchSenderType::Broker(BrokerBatchSender::new(config).await?)
             }
             ResultSenderAdapter::Socket(config) => {
                 BatchSenderType::Socket(SocketBatchSender::new(config).await?)
             }
         };
-        Ok(BatchSender { rx, connector })
+        Ok(BatchSender {
+            rx,
+            connector,
+            health_check_interval,
+            flush_interval,
+        })
     }
 
+    /// Forward job results until every sender of the channel is dropped, the backlog
+    /// is flushed periodically so results never wait longer than the batch interval,
+    /// and once more before returning so nothing is lost on shutdown.
     pub async fn run(&mut self) {
-        while let Some(job) = self.rx.recv().await {
-            match self.connector.send(job).await {
-                Ok(_) => {}
-                Err(e) => {
-                    tracing::error!("Unable to send job result {:?}", e);
+        let mut health_check = tokio::time::interval(self.health_check_interval);
+        health_check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut flush = tokio::time::interval(self.flush_interval);
+        flush.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                job = self.rx.recv() => {
+                    let Some(job) = job else {
+                        break;
+                    };
+                    if let Err(e) = self.connector.send(job).await {
+                        tracing::error!("Unable to send job result {:?}", e);
+                    }
+                }
+                _ = flush.tick() => {
+                    if let Err(e) = self.connector.flush().await {
+                        tracing::error!("Unable to flush job results {:?}", e);
+                    }
+                }
+                _ = health_check.tick() => {
+                    if let Err(e) = self.connector.health_check().await {
+                        tracing::error!("Result sender is unhealthy {:?}", e);
+                    }
                 }
             }
         }
+
+        tracing::info!("Result channel closed, flushing remaining job results");
+        if let Err(e) = self.connector.flush().await {
+            tracing::error!("Unable to flush job results {:?}", e);
+        }
     }
 }
 
@@ -87,9 +155,16 @@ pub enum BatchSenderError {
     WriteSocketError(String),
     #[error("Unable to connect to socket: {0}")]
     OpenSocketError(String),
+    #[error("Unable to access the spool of undelivered batches: {0}")]
+    SpoolError(String),
+    #[error("Provided broker token isn't a valid gRPC metadata value")]
+    InvalidBrokerToken,
+    #[error("Invalid broker TLS configuration: {0}")]
+    InvalidTlsConfiguration(String),
 }
 
 #[enum_dispatch(BatchSenderOutput)]
+#[allow(clippy::large_enum_variant)]
 pub enum BatchSenderType {
     Stdout(StdoutBatchSender),
     Broker(BrokerBatchSender),
@@ -100,6 +175,9 @@ pub enum BatchSenderType {
 pub trait BatchSenderOutput {
     async fn send(&mut self, job_result: JobResult) -> Result<(), BatchSenderError>;
 
+    /// Deliver any buffered job result right away
+    async fn flush(&mut self) -> Result<(), BatchSenderError>;
+
     async fn health_check(&mut self) -> Result<(), BatchSenderError>;
 }
 
@@ -119,22 +197,13 @@ impl SocketBatchSender {
 impl BatchSenderOutput for SocketBatchSender {
     async fn send(&mut self, job_result: JobResult) -> Result<(), BatchSenderError> {
         let request = isok_data::broker_rpc::CheckBatchRequest {
-            created_at: None,
+            created_at: Some(SystemTime::now().into()),
             tags: Some(Tags {
                 agent_id: "local-agent".to_string(),
                 zone: "dev".to_string(),
                 region: "localhost".to_string(),
             }),
-            events: vec![CheckResult {
-                id_ulid: job_result.id.clone().to_string(),
-                run_at: None,
-                status: job_result.status.into(),
-                metrics: Some(CheckJobMetrics {
-                    latency: job_result.latency.map(|d| d.as_millis() as u64),
-                }),
-                tags: None,
-                details: job_result.details,
-            }],
+            events: vec![job_result.into()],
         };
         let mut buffer = Vec::new();
         request.encode(&mut buffer).unwrap();
@@ -159,6 +228,13 @@ impl BatchSenderOutput for SocketBatchSender {
         Ok(())
     }
 
+    async fn flush(&mut self) -> Result<(), BatchSenderError> {
+        self.stream
+            .flush()
+            .await
+            .map_err(|e| BatchSenderError::WriteSocketError(e.to_string()))
+    }
+
     async fn health_check(&mut self) -> Result<(), BatchSenderError> {
         self.stream
             .writable()
@@ -177,7 +253,11 @@ impl StdoutBatchSender {
 
 impl BatchSenderOutput for StdoutBatchSender {
     async fn send(&mut self, job_result: JobResult) -> Result<(), BatchSenderError> {
-        tracing::info!(job_id = ?job_result.id, job_status = ?job_result.status, "Job result");
+        tracing::info!(job_id = ?job_result.id, job_status = ?job_result.status, job_reason = ?job_result.reason, "Job result");
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), BatchSenderError> {
         Ok(())
     }
 
@@ -186,8 +266,21 @@ impl BatchSenderOutput for StdoutBatchSender {
     }
 }
 
-pub struct BrokerBatchSender {
+/// A broker the agent is able to send its results to
+struct BrokerEndpoint {
+    address: String,
     client: BrokerGrpcClient,
+}
+
+pub struct BrokerBatchSender {
+    /// Brokers by order of priority, the main broker being the first one
+    brokers: Vec<BrokerEndpoint>,
+    /// Index of the broker batches are currently sent to
+    active: usize,
+    /// Batches that couldn't be delivered, when enabled
+    spool: Option<Spool>,
+    /// `authorization` metadata sent along every request, when a token is configured
+    authorization: Option<MetadataValue<Ascii>>,
     zone: String,
     region: String,
     agent_id: String,
@@ -203,89 +296,311 @@ pub struct BrokerBatchSender {
 impl BrokerBatchSender {
     const MAX_RETRY_COUNT: u8 = 3;
     const DELAY_BETWEEN_RETRIES: Duration = Duration::from_secs(2);
+    const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+    const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
     pub async fn new(config: BrokerConfig) -> Result<Self, BatchSenderError> {
-        let client = BrokerClient::connect(config.main_broker.clone())
-            .await
-            .map_err(|_| BatchSenderError::InvalidBrokerEndpointConfiguration)?;
-        Self::new_with_client(config, client).await
+        let addresses: Vec<_> = std::iter::once(&config.main_broker)
+            .chain(config.fallback_brokers.iter())
+            .collect();
+        let clients = Self::connect_lazy(&addresses, config.tls.as_ref()).await?;
+        Self::new_with_clients(config, clients).await
+    }
+
+    /// Create a client for each broker address, verified with `tls` when set
+    pub(crate) async fn connect_lazy(
+        addresses: &[&String],
+        tls: Option<&BrokerTlsConfig>,
+    ) -> Result<Vec<BrokerClient<Channel>>, BatchSenderError> {
+        let tls = match tls {
+            Some(tls_config) => Some(Self::client_tls_config(tls_config).await?),
+            None => None,
+        };
+
+        // Connections are lazy, the agent must be able to start even though
+        // some of its brokers are down
+        addresses
+            .iter()
+            .map(|address| {
+                let mut endpoint = Endpoint::from_shared(address.to_string())
+                    .map_err(|_| BatchSenderError::InvalidBrokerEndpointConfiguration)?;
+                let is_https = endpoint.uri().scheme_str() == Some("https");
+                let tls = match &tls {
+                    Some(_) if !is_https => {
+                        return Err(BatchSenderError::InvalidTlsConfiguration(format!(
+                            "TLS is configured but broker address {} isn't https",
+                            address
+                        )));
+                    }
+                    Some(tls) => Some(tls.clone()),
+                    None if is_https => Some(ClientTlsConfig::new().with_native_roots()),
+                    None => None,
+                };
+                if let Some(tls) = tls {
+                    endpoint = endpoint
+                        .tls_config(tls)
+                        .map_err(|e| BatchSenderError::InvalidTlsConfiguration(e.to_string()))?;
+                }
+                Ok(BrokerClient::new(
+                    endpoint
+                        .connect_timeout(Self::CONNECT_TIMEOUT)
+                        .connect_lazy(),
+                ))
+            })
+            .collect()
+    }
+
+    /// `authorization` metadata carrying the agent token
+    pub(crate) fn authorization(
+        token: Option<&str>,
+    ) -> Result<Option<MetadataValue<Ascii>>, BatchSenderError> {
+        token
+            .map(|token| {
+                MetadataValue::try_from(format!("Bearer {}", token.trim()))
+                    .map_err(|_| BatchSenderError::InvalidBrokerToken)
+            })
+            .transpose()
+    }
+
+    /// Load the certificates used to verify brokers and, for mutual TLS, to authenticate
+    /// the agent
+    async fn client_tls_config(
+        config: &BrokerTlsConfig,
+    ) -> Result<ClientTlsConfig, BatchSenderError> {
+        let mut tls = ClientTlsConfig::new();
+        tls = match &config.ca_cert {
+            Some(path) => tls.ca_certificate(Certificate::from_pem(read_pem(path).await?)),
+            None => tls.with_native_roots(),
+        };
+        match (&config.client_cert, &config.client_key) {
+            (Some(cert), Some(key)) => {
+                tls = tls.identity(Identity::from_pem(
+                    read_pem(cert).await?,
+                    read_pem(key).await?,
+                ));
+            }
+            (None, None) => {}
+            _ => {
+                return Err(BatchSenderError::InvalidTlsConfiguration(
+                    "Both client_cert and client_key are required for mutual TLS".to_string(),
+                ));
+            }
+        }
+        if let Some(domain_name) = &config.domain_name {
+            tls = tls.domain_name(domain_name.clone());
+        }
+        Ok(tls)
     }
 
-    pub async fn new_with_client(
+    /// Create a sender from already connected clients, the first one being the main
+    /// broker, the following ones the fallback brokers in the configuration order.
+    pub async fn new_with_clients(
         config: BrokerConfig,
-        client: BrokerClient<Channel>,
+        clients: Vec<BrokerClient<Channel>>,
     ) -> Result<Self, BatchSenderError> {
         if config.batch_interval == 0 {
             tracing::warn!("Batch interval is set to 0, batch will be sent immediately");
         }
+        if clients.is_empty() {
+            return Err(BatchSenderError::InvalidBrokerEndpointConfiguration);
+        }
+
+        let authorization = Self::authorization(config.token.as_deref())?;
+        let spool = match config.spool {
+            Some(spool_config) => Some(Spool::open(spool_config).await?),
+            None => None,
+        };
 
         let batch = config.batch.max(1);
+        let brokers = std::iter::once(config.main_broker)
+            .chain(config.fallback_brokers)
+            .zip(clients)
+            .map(|(address, client)| BrokerEndpoint { address, client })
+            .collect();
 
         Ok(BrokerBatchSender {
-            client,
+            brokers,
+            active: 0,
+            spool,
+            authorization,
             backlog: Vec::with_capacity(batch as usize),
             agent_id: config.agent_id,
             zone: config.zone,
             region: config.region,
-            batch: batch as u64,
+            batch,
             batch_interval: config.batch_interval,
             last_batch: Instant::now(),
         })
     }
 
+    /// Wrap a message into a request, authenticated with the agent token if any
+    fn request<T>(&self, message: T) -> tonic::Request<T> {
+        let mut request = tonic::Request::new(message);
+        if let Some(authorization) = &self.authorization {
+            request
+                .metadata_mut()
+                .insert("authorization", authorization.clone());
+        }
+        request
+    }
+
     async fn drain_and_send(&mut self) -> Result<(), BatchSenderError> {
         if self.backlog.is_empty() {
             return Ok(());
         }
         let events = self.backlog.drain(..).map(|e| e.into()).collect();
-        self.send_batch(events, None).await?;
+        let batch_request = CheckBatchRequest {
+            created_at: Some(SystemTime::now().into()),
+            tags: Some(Tags {
+                agent_id: self.agent_id.clone(),
+                zone: self.zone.clone(),
+                region: self.region.clone(),
+            }),
+            events,
+        };
         self.last_batch = Instant::now();
 
+        let Some(spool) = self.spool.clone() else {
+            return self.send_with_failover(&batch_request).await;
+        };
+
+        // Spooled batches are sent first to preserve ordering, if any of them can't be
+        // delivered, the new batch goes to the spool as well.
+        let result = match self.replay_spool().await {
+            Ok(()) => self.send_with_failover(&batch_request).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            tracing::warn!("Unable to deliver batch, storing it in the spool: {}", e);
+            spool.push(&batch_request).await?;
+        }
         Ok(())
     }
 
-    async fn send_batch(
+    /// Send spooled batches in order, stopping at the first one that can't be delivered
+    async fn replay_spool(&mut self) -> Result<(), BatchSenderError> {
+        let Some(spool) = self.spool.clone() else {
+            return Ok(());
+        };
+        while let Some((entry, batch_request)) = spool.peek().await? {
+            self.send_with_failover(&batch_request).await?;
+            spool.remove(entry).await?;
+            tracing::info!(
+                batch_size = batch_request.events.len(),
+                "Replayed spooled batch"
+            );
+        }
+        Ok(())
+    }
+
+    /// Send the batch to the active broker. If it keeps failing, the batch is sent
+    /// to the first healthy fallback, which becomes the active broker.
+    async fn send_with_failover(
         &mut self,
-        events: Vec<CheckResult>,
-        retry_count: Option<u8>,
+        batch_request: &CheckBatchRequest,
     ) -> Result<(), BatchSenderError> {
-        let retry_count = retry_count.unwrap_or(0);
-        let batch_request = isok_data::broker_rpc::CheckBatchRequest {
-            created_at: None,
-            tags: Some(Tags {
-                agent_id: self.agent_id.clone(),
-                zone: self.zone.clone(),
-                region: self.region.clone(),
-            }),
-            events: events.clone(),
+        let error = match self
+            .send_batch(self.active, batch_request, Self::MAX_RETRY_COUNT)
+            .await
+        {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
         };
 
-        match self.client.batch_send(batch_request).await {
-            Ok(_) => Ok(()),
-            Err(e) if retry_count < Self::MAX_RETRY_COUNT => {
-                tracing::warn!(code = ?e.code(), message = ?e.message(), "Batch send failed, retrying");
-                tokio::time::sleep(Self::DELAY_BETWEEN_RETRIES).await;
-                Box::pin(self.send_batch(events, Some(retry_count + 1))).await
+        for (index, healthy) in self.probe_brokers().await.into_iter().enumerate() {
+            if index == self.active || !healthy {
+                continue;
+            }
+            if self.send_batch(index, batch_request, 0).await.is_ok() {
+                self.switch_to(index);
+                return Ok(());
             }
-            Err(e) => Err(BatchSenderError::UnableToSendBatch(e.to_string())),
         }
+        Err(error)
+    }
+
+    async fn send_batch(
+        &mut self,
+        index: usize,
+        batch_request: &CheckBatchRequest,
+        max_retries: u8,
+    ) -> Result<(), BatchSenderError> {
+        let mut retry_count = 0;
+        loop {
+            let request = self.request(batch_request.clone());
+            let broker = &mut self.brokers[index];
+            match broker.client.batch_send(request).await {
+                Ok(_) => return Ok(()),
+                Err(e) if retry_count < max_retries => {
+                    tracing::warn!(broker = broker.address, code = ?e.code(), message = ?e.message(), "Batch send failed, retrying");
+                    tokio::time::sleep(Self::DELAY_BETWEEN_RETRIES).await;
+                    retry_count += 1;
+                }
+                Err(e) => {
+                    tracing::warn!(broker = broker.address, code = ?e.code(), message = ?e.message(), "Batch send failed");
+                    return Err(BatchSenderError::UnableToSendBatch(e.to_string()));
+                }
+            }
+        }
+    }
+
+    async fn is_healthy(&self, index: usize) -> bool {
+        let request = self.request(isok_data::broker_rpc::HealthRequest {});
+        let mut client = self.brokers[index].client.clone();
+        match tokio::time::timeout(Self::HEALTH_CHECK_TIMEOUT, client.health(request)).await {
+            Ok(Ok(response)) => response.get_ref().healthy,
+            _ => false,
+        }
+    }
+
+    /// Probe every broker at the same time, so an unreachable broker only delays
+    /// the probe by [Self::HEALTH_CHECK_TIMEOUT] once
+    async fn probe_brokers(&self) -> Vec<bool> {
+        join_all((0..self.brokers.len()).map(|index| self.is_healthy(index))).await
+    }
+
+    fn switch_to(&mut self, index: usize) {
+        tracing::warn!(
+            from = self.brokers[self.active].address,
+            to = self.brokers[index].address,
+            "Switching active broker"
+        );
+        self.active = index;
     }
 }
 
 impl From<JobResult> for CheckResult {
     fn from(value: JobResult) -> Self {
+        let mut metrics = CheckJobMetrics {
+            latency: value.latency.map(|d| d.as_millis() as u64),
+            ..Default::default()
+        };
+        if let Some(timings) = value.timings {
+            timings.record(&mut metrics);
+        }
         Self {
             id_ulid: value.id.to_string(),
-            run_at: None,
+            run_at: Some(value.run_at.into()),
             status: value.status.into(),
-            metrics: Some(CheckJobMetrics {
-                latency: value.latency.map(|d| d.as_millis() as u64),
-            }),
+            metrics: Some(metrics),
             tags: None,
+            reason: value.reason,
+            received_at: None,
             details: value.details,
         }
     }
 }
 
+async fn read_pem(path: &Path) -> Result<Vec<u8>, BatchSenderError> {
+    tokio::fs::read(path).await.map_err(|e| {
+        BatchSenderError::InvalidTlsConfiguration(format!(
+            "Unable to read {}: {}",
+            path.display(),
+            e
+        ))
+    })
+}
+
 impl BatchSenderOutput for BrokerBatchSender {
     async fn send(&mut self, job_result: JobResult) -> Result<(), BatchSenderError> {
         self.backlog.push(job_result);
@@ -307,33 +622,44 @@ impl BatchSenderOutput for BrokerBatchSender {
         Ok(())
     }
 
+    async fn flush(&mut self) -> Result<(), BatchSenderError> {
+        if !self.backlog.is_empty() {
+            tracing::debug!(batch_size = self.backlog.len(), "Flushing backlog");
+        }
+        self.drain_and_send().await
+    }
+
+    /// Probe brokers by order of priority and make the first healthy one active,
+    /// so the agent fails back to its main broker as soon as it recovers. Spooled
+    /// batches are replayed once a healthy broker is found.
     async fn health_check(&mut self) -> Result<(), BatchSenderError> {
-        match self
-            .client
-            .health(isok_data::broker_rpc::HealthRequest {})
-            .await
-        {
-            Ok(response) => {
-                if !response.get_ref().healthy {
-                    return Err(BatchSenderError::BrokerUnhealthy);
+        for (index, healthy) in self.probe_brokers().await.into_iter().enumerate() {
+            if healthy {
+                if index != self.active {
+                    self.switch_to(index);
                 }
-                Ok(())
+                return self.replay_spool().await;
             }
-            Err(_) => Err(BatchSenderError::BrokerUnhealthy),
+            tracing::warn!(broker = self.brokers[index].address, "Broker is unhealthy");
         }
+        Err(BatchSenderError::BrokerUnhealthy)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::SpoolConfig;
     use async_trait::async_trait;
     use hyper_util::rt::TokioIo;
     use isok_data::broker_rpc::broker_server::{Broker, BrokerServer};
     use isok_data::broker_rpc::{
-        CheckBatchRequest, CheckBatchResponse, HealthRequest, HealthResponse,
+        CheckBatchRequest, CheckBatchResponse, GetChecksRequest, GetChecksResponse, HealthRequest,
+        HealthResponse,
     };
     use pretty_assertions::assert_eq;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
     use std::time::Duration;
     use tokio::io::DuplexStream;
     use tonic::codegen::tokio_stream;
@@ -344,9 +670,51 @@ mod tests {
     struct DummyBroker {
         batch_send_response: CheckBatchResponse,
         health_response: HealthResponse,
+        fail_batch_send: bool,
+        /// Never answer health probes
+        hang_health: bool,
+        received_batches: Arc<AtomicUsize>,
+        required_authorization: Option<&'static str>,
     }
 
     impl DummyBroker {
+        fn healthy() -> Self {
+            DummyBroker {
+                health_response: HealthResponse { healthy: true },
+                ..Default::default()
+            }
+        }
+
+        fn failing() -> Self {
+            DummyBroker {
+                fail_batch_send: true,
+                ..Default::default()
+            }
+        }
+
+        fn hanging() -> Self {
+            DummyBroker {
+                hang_health: true,
+                ..Default::default()
+            }
+        }
+
+        fn is_authorized<T>(&self, request: &tonic::Request<T>) -> bool {
+            match self.required_authorization {
+                Some(required) => request
+                    .metadata()
+                    .get("authorization")
+                    .is_some_and(|value| value == required),
+                None => true,
+            }
+        }
+
+        async fn spawn(self) -> BrokerClient<Channel> {
+            let (client, server) = tokio::io::duplex(1024);
+            let _server = self.spawn_server(server);
+            Self::create_client(client).await
+        }
+
         fn spawn_server(self, server: DuplexStream) -> tokio::task::JoinHandle<()> {
             tokio::spawn(async move {
                 let broker = Server::builder()
@@ -367,10 +735,7 @@ mod tests {
                         if let Some(client) = client {
                             Ok(TokioIo::new(client))
                         } else {
-                            Err(std::io::Error::new(
-                                std::io::ErrorKind::Other,
-                                "Client already taken",
-                            ))
+                            Err(std::io::Error::other("Client already taken"))
                         }
                     }
                 }))
@@ -384,16 +749,36 @@ mod tests {
     impl Broker for DummyBroker {
         async fn batch_send(
             &self,
-            _: tonic::Request<CheckBatchRequest>,
+            request: tonic::Request<CheckBatchRequest>,
         ) -> Result<tonic::Response<CheckBatchResponse>, tonic::Status> {
+            if !self.is_authorized(&request) {
+                return Err(tonic::Status::unauthenticated("Invalid token"));
+            }
+            if self.fail_batch_send {
+                return Err(tonic::Status::unavailable("Broker is failing"));
+            }
+            self.received_batches.fetch_add(1, Ordering::SeqCst);
             Ok(tonic::Response::new(self.batch_send_response.clone()))
         }
 
         async fn health(
             &self,
-            _: tonic::Request<HealthRequest>,
+            request: tonic::Request<HealthRequest>,
         ) -> Result<tonic::Response<HealthResponse>, tonic::Status> {
-            Ok(tonic::Response::new(self.health_response.clone()))
+            if !self.is_authorized(&request) {
+                return Err(tonic::Status::unauthenticated("Invalid token"));
+            }
+            if self.hang_health {
+                std::future::pending::<()>().await;
+            }
+            Ok(tonic::Response::new(self.health_response))
+        }
+
+        async fn get_checks(
+            &self,
+            _request: tonic::Request<GetChecksRequest>,
+        ) -> Result<tonic::Response<GetChecksResponse>, tonic::Status> {
+            Err(tonic::Status::unimplemented("Checks aren't served"))
         }
     }
 
@@ -406,6 +791,17 @@ mod tests {
             region: "localhost".to_string(),
             batch,
             batch_interval,
+            health_check_interval: 10,
+            spool: None,
+            token: None,
+            tls: None,
+        }
+    }
+
+    fn create_broker_config_with_fallback(batch: u64) -> BrokerConfig {
+        BrokerConfig {
+            fallback_brokers: vec!["127.0.0.1:50552".to_string()],
+            ..create_broker_config(batch, 0)
         }
     }
 
@@ -413,15 +809,15 @@ mod tests {
     #[tokio::test]
     async fn test_batch_sender() {
         let (client, server) = tokio::io::duplex(1024);
-        let _ = DummyBroker::default().spawn_server(server);
+        let _server = DummyBroker::default().spawn_server(server);
         let client = DummyBroker::create_client(client).await;
         let config = create_broker_config(10, 5);
-        let mut sender = BrokerBatchSender::new_with_client(config, client)
+        let mut sender = BrokerBatchSender::new_with_clients(config, vec![client])
             .await
             .expect("Expected to create batch sender");
 
         let job_result = JobResult::new(JobId::generate());
-        let snapshot_last_batch = sender.last_batch.clone();
+        let snapshot_last_batch = sender.last_batch;
         sender.send(job_result).await.unwrap();
         assert_eq!(sender.backlog.len(), 1);
 
@@ -438,15 +834,15 @@ mod tests {
     #[tokio::test]
     async fn test_batch_sender_with_zero_batch() {
         let (client, server) = tokio::io::duplex(1024);
-        let _ = DummyBroker::default().spawn_server(server);
+        let _server = DummyBroker::default().spawn_server(server);
         let client = DummyBroker::create_client(client).await;
         let config = create_broker_config(0, 5);
-        let mut sender = BrokerBatchSender::new_with_client(config, client)
+        let mut sender = BrokerBatchSender::new_with_clients(config, vec![client])
             .await
             .expect("Expected to create batch sender");
 
         let job_result = JobResult::new(JobId::generate());
-        let snapshot_last_batch = sender.last_batch.clone();
+        let snapshot_last_batch = sender.last_batch;
         sender.send(job_result).await.unwrap();
         assert_eq!(sender.backlog.len(), 0);
         // We should have sent the batch
@@ -458,18 +854,272 @@ mod tests {
     #[tokio::test]
     async fn test_batch_sender_with_zero_interval() {
         let (client, server) = tokio::io::duplex(1024);
-        let _ = DummyBroker::default().spawn_server(server);
+        let _server = DummyBroker::default().spawn_server(server);
         let client = DummyBroker::create_client(client).await;
         let config = create_broker_config(10, 0);
-        let mut sender = BrokerBatchSender::new_with_client(config, client)
+        let mut sender = BrokerBatchSender::new_with_clients(config, vec![client])
             .await
             .expect("Expected to create batch sender");
 
         let job_result = JobResult::new(JobId::generate());
-        let snapshot_last_batch = sender.last_batch.clone();
+        let snapshot_last_batch = sender.last_batch;
         sender.send(job_result).await.unwrap();
         assert_eq!(sender.backlog.len(), 0);
         // We should have sent the batch
         assert!(sender.last_batch > snapshot_last_batch);
     }
+
+    // When the main broker fails, the batch is sent to the fallback broker
+    // which becomes the active one
+    #[tokio::test]
+    async fn test_batch_sender_failover() {
+        let fallback = DummyBroker::healthy();
+        let fallback_batches = fallback.received_batches.clone();
+        let clients = vec![DummyBroker::failing().spawn().await, fallback.spawn().await];
+        let mut sender =
+            BrokerBatchSender::new_with_clients(create_broker_config_with_fallback(1), clients)
+                .await
+                .expect("Expected to create batch sender");
+
+        sender
+            .send(JobResult::new(JobId::generate()))
+            .await
+            .expect("Expected batch to be sent to the fallback broker");
+        assert_eq!(sender.active, 1);
+        assert_eq!(fallback_batches.load(Ordering::SeqCst), 1);
+    }
+
+    // The sender switches to a healthy fallback when the main broker is unhealthy,
+    // and fails back to the main broker once it recovers
+    #[tokio::test]
+    async fn test_batch_sender_health_check_failover_and_failback() {
+        let clients = vec![
+            DummyBroker::default().spawn().await,
+            DummyBroker::healthy().spawn().await,
+        ];
+        let mut sender =
+            BrokerBatchSender::new_with_clients(create_broker_config_with_fallback(1), clients)
+                .await
+                .expect("Expected to create batch sender");
+
+        sender
+            .health_check()
+            .await
+            .expect("Expected a healthy broker");
+        assert_eq!(sender.active, 1);
+
+        sender.brokers[0].client = DummyBroker::healthy().spawn().await;
+        sender
+            .health_check()
+            .await
+            .expect("Expected a healthy broker");
+        assert_eq!(sender.active, 0);
+    }
+
+    #[tokio::test]
+    async fn test_batch_sender_health_check_all_unhealthy() {
+        let clients = vec![
+            DummyBroker::default().spawn().await,
+            DummyBroker::default().spawn().await,
+        ];
+        let mut sender =
+            BrokerBatchSender::new_with_clients(create_broker_config_with_fallback(1), clients)
+                .await
+                .expect("Expected to create batch sender");
+
+        assert!(sender.health_check().await.is_err());
+        assert_eq!(sender.active, 0);
+    }
+
+    // Brokers are probed at the same time, unresponsive ones only delay the
+    // health check by a single timeout
+    #[tokio::test(start_paused = true)]
+    async fn test_batch_sender_health_check_probes_concurrently() {
+        let clients = vec![
+            DummyBroker::hanging().spawn().await,
+            DummyBroker::hanging().spawn().await,
+            DummyBroker::healthy().spawn().await,
+        ];
+        let config = BrokerConfig {
+            fallback_brokers: vec!["127.0.0.1:50552".to_string(), "127.0.0.1:50553".to_string()],
+            ..create_broker_config(1, 0)
+        };
+        let mut sender = BrokerBatchSender::new_with_clients(config, clients)
+            .await
+            .expect("Expected to create batch sender");
+
+        let start = Instant::now();
+        sender
+            .health_check()
+            .await
+            .expect("Expected a healthy broker");
+        assert_eq!(sender.active, 2);
+        assert!(start.elapsed() < BrokerBatchSender::HEALTH_CHECK_TIMEOUT * 2);
+    }
+
+    // Batches that can't be delivered are spooled, then replayed once
+    // a broker is healthy again
+    #[tokio::test(start_paused = true)]
+    async fn test_batch_sender_spool_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = BrokerConfig {
+            spool: Some(SpoolConfig {
+                path: dir.path().to_path_buf(),
+                max_size: 1024 * 1024,
+                max_age: 3600,
+            }),
+            ..create_broker_config(1, 0)
+        };
+        let mut sender =
+            BrokerBatchSender::new_with_clients(config, vec![DummyBroker::failing().spawn().await])
+                .await
+                .expect("Expected to create batch sender");
+
+        sender
+            .send(JobResult::new(JobId::generate()))
+            .await
+            .expect("Expected the batch to be spooled");
+        let spool = sender.spool.clone().unwrap();
+        assert!(spool.peek().await.unwrap().is_some());
+
+        let broker = DummyBroker::healthy();
+        let received_batches = broker.received_batches.clone();
+        sender.brokers[0].client = broker.spawn().await;
+        sender
+            .health_check()
+            .await
+            .expect("Expected a healthy broker");
+        assert!(spool.peek().await.unwrap().is_none());
+        assert_eq!(received_batches.load(Ordering::SeqCst), 1);
+    }
+
+    // The configured token is sent along every call to the broker
+    #[tokio::test]
+    async fn test_batch_sender_sends_token() {
+        let broker = DummyBroker {
+            required_authorization: Some("Bearer agent-token"),
+            ..DummyBroker::healthy()
+        };
+        let received_batches = broker.received_batches.clone();
+        let config = BrokerConfig {
+            token: Some("agent-token".to_string()),
+            ..create_broker_config(1, 0)
+        };
+        let mut sender = BrokerBatchSender::new_with_clients(config, vec![broker.spawn().await])
+            .await
+            .expect("Expected to create batch sender");
+
+        sender
+            .health_check()
+            .await
+            .expect("Expected the broker to accept the token");
+        sender
+            .send(JobResult::new(JobId::generate()))
+            .await
+            .expect("Expected the broker to accept the token");
+        assert_eq!(received_batches.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_batch_sender_invalid_tls_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let cert = dir.path().join("client.pem");
+        std::fs::write(&cert, "").unwrap();
+        for (main_broker, tls) in [
+            // Client certificate without its key
+            (
+                "https://localhost:50551",
+                BrokerTlsConfig {
+                    client_cert: Some(cert),
+                    ..Default::default()
+                },
+            ),
+            // Missing CA bundle
+            (
+                "https://localhost:50551",
+                BrokerTlsConfig {
+                    ca_cert: Some(dir.path().join("missing.pem")),
+                    ..Default::default()
+                },
+            ),
+            // TLS configured for a plaintext broker
+            ("http://localhost:50551", BrokerTlsConfig::default()),
+        ] {
+            let config = BrokerConfig {
+                main_broker: main_broker.to_string(),
+                tls: Some(tls),
+                ..create_broker_config(1, 0)
+            };
+            assert!(matches!(
+                BrokerBatchSender::new(config).await,
+                Err(BatchSenderError::InvalidTlsConfiguration(_))
+            ));
+        }
+    }
+
+    fn create_broker_batch_sender(
+        sender: BrokerBatchSender,
+        rx: UnboundedReceiver<JobResult>,
+        flush_interval: Duration,
+    ) -> BatchSender {
+        BatchSender {
+            connector: BatchSenderType::Broker(sender),
+            rx,
+            health_check_interval: Duration::from_secs(3600),
+            flush_interval,
+        }
+    }
+
+    // A partial backlog is flushed on the timer, without waiting for a new result
+    #[tokio::test(start_paused = true)]
+    async fn test_batch_sender_flushes_on_interval() {
+        let broker = DummyBroker::healthy();
+        let received_batches = broker.received_batches.clone();
+        let sender = BrokerBatchSender::new_with_clients(
+            create_broker_config(10, 1),
+            vec![broker.spawn().await],
+        )
+        .await
+        .expect("Expected to create batch sender");
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut batch_sender = create_broker_batch_sender(sender, rx, Duration::from_secs(1));
+        let run = tokio::spawn(async move { batch_sender.run().await });
+
+        // Time is paused, it only advances when every task is idle
+        tx.send(JobResult::new(JobId::generate())).unwrap();
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert_eq!(received_batches.load(Ordering::SeqCst), 1);
+
+        drop(tx);
+        run.await.unwrap();
+    }
+
+    // The backlog is flushed once every sender of the channel is dropped
+    #[tokio::test]
+    async fn test_batch_sender_flushes_on_shutdown() {
+        let broker = DummyBroker::healthy();
+        let received_batches = broker.received_batches.clone();
+        let sender = BrokerBatchSender::new_with_clients(
+            create_broker_config(10, 3600),
+            vec![broker.spawn().await],
+        )
+        .await
+        .expect("Expected to create batch sender");
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let mut batch_sender = create_broker_batch_sender(sender, rx, Duration::from_secs(3600));
+
+        tx.send(JobResult::new(JobId::generate())).unwrap();
+        tx.send(JobResult::new(JobId::generate())).unwrap();
+        drop(tx);
+        batch_sender.run().await;
+        assert_eq!(received_batches.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_check_result_run_at() {
+        let job_result = JobResult::new(JobId::generate());
+        let run_at = job_result.run_at;
+        let check_result: CheckResult = job_result.into();
+        assert_eq!(check_result.run_at, Some(run_at.into()));
+    }
 }
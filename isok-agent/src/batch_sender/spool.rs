@@ -0,0 +1,295 @@
+use crate::batch_sender::BatchSenderError;
+use crate::config::SpoolConfig;
+use isok_data::broker_rpc::CheckBatchRequest;
+use prost::Message;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+
+/// Disk-backed queue of batches that couldn't be delivered to any broker.
+///
+/// Each batch is stored in its own file, named after the time it was spooled, so
+/// batches are replayed in order and survive agent restarts. The spool is bounded
+/// both in size and age, the oldest batches being dropped first.
+#[derive(Debug, Clone)]
+pub(crate) struct Spool {
+    config: SpoolConfig,
+    /// Spooled batches, listed from the directory when the spool is opened and
+    /// kept up to date afterwards
+    index: Arc<Mutex<SpoolIndex>>,
+}
+
+/// A batch stored in the spool
+#[derive(Debug, Clone)]
+pub(crate) struct SpoolEntry {
+    path: PathBuf,
+    spooled_at: SystemTime,
+    size: u64,
+}
+
+/// Spooled batches from the oldest to the newest, along with their total size
+#[derive(Debug, Default)]
+struct SpoolIndex {
+    entries: VecDeque<SpoolEntry>,
+    size: u64,
+}
+
+impl SpoolIndex {
+    fn push_back(&mut self, entry: SpoolEntry) {
+        self.size += entry.size;
+        self.entries.push_back(entry);
+    }
+
+    fn pop_front(&mut self) -> Option<SpoolEntry> {
+        let entry = self.entries.pop_front()?;
+        self.size -= entry.size;
+        Some(entry)
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some(index) = self.entries.iter().position(|entry| entry.path == path) {
+            if let Some(entry) = self.entries.remove(index) {
+                self.size -= entry.size;
+            }
+        }
+    }
+}
+
+impl Spool {
+    const EXTENSION: &'static str = "batch";
+
+    pub(crate) async fn open(config: SpoolConfig) -> Result<Self, BatchSenderError> {
+        tokio::fs::create_dir_all(&config.path)
+            .await
+            .map_err(|e| BatchSenderError::SpoolError(e.to_string()))?;
+        let mut index = SpoolIndex::default();
+        for entry in Self::scan(&config.path).await? {
+            index.push_back(entry);
+        }
+        let spool = Spool {
+            config,
+            index: Arc::new(Mutex::new(index)),
+        };
+        spool.prune(&mut *spool.index.lock().await).await?;
+        Ok(spool)
+    }
+
+    /// Store a batch at the end of the queue
+    pub(crate) async fn push(&self, batch: &CheckBatchRequest) -> Result<(), BatchSenderError> {
+        let mut index = self.index.lock().await;
+        let mut timestamp = nanos_since_epoch(SystemTime::now());
+        // Batches spooled within the same nanosecond, or after a clock change, keep
+        // their order
+        if let Some(newest) = index.entries.back() {
+            timestamp = timestamp.max(nanos_since_epoch(newest.spooled_at) + 1);
+        }
+        let path = self.entry_path(timestamp);
+
+        // Write to a temporary file first, so a crash never leaves a truncated batch
+        let content = batch.encode_to_vec();
+        let tmp_path = path.with_extension("tmp");
+        tokio::fs::write(&tmp_path, &content)
+            .await
+            .map_err(|e| BatchSenderError::SpoolError(e.to_string()))?;
+        tokio::fs::rename(&tmp_path, &path)
+            .await
+            .map_err(|e| BatchSenderError::SpoolError(e.to_string()))?;
+        index.push_back(SpoolEntry {
+            path,
+            spooled_at: UNIX_EPOCH + Duration::from_nanos(timestamp),
+            size: content.len() as u64,
+        });
+
+        self.prune(&mut index).await
+    }
+
+    /// Oldest batch of the queue, corrupted entries are discarded
+    pub(crate) async fn peek(
+        &self,
+    ) -> Result<Option<(SpoolEntry, CheckBatchRequest)>, BatchSenderError> {
+        let mut index = self.index.lock().await;
+        while let Some(entry) = index.entries.front().cloned() {
+            let content = tokio::fs::read(&entry.path)
+                .await
+                .map_err(|e| BatchSenderError::SpoolError(e.to_string()))?;
+            match CheckBatchRequest::decode(content.as_slice()) {
+                Ok(batch) => return Ok(Some((entry, batch))),
+                Err(e) => {
+                    tracing::error!(path = ?entry.path, "Discarding corrupted spooled batch: {}", e);
+                    index.pop_front();
+                    Self::remove_file(&entry).await?;
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    pub(crate) async fn remove(&self, entry: SpoolEntry) -> Result<(), BatchSenderError> {
+        self.index.lock().await.remove(&entry.path);
+        Self::remove_file(&entry).await
+    }
+
+    async fn remove_file(entry: &SpoolEntry) -> Result<(), BatchSenderError> {
+        tokio::fs::remove_file(&entry.path)
+            .await
+            .map_err(|e| BatchSenderError::SpoolError(e.to_string()))
+    }
+
+    fn entry_path(&self, timestamp: u64) -> PathBuf {
+        self.config
+            .path
+            .join(format!("{:020}.{}", timestamp, Self::EXTENSION))
+    }
+
+    /// Batches spooled in `path`, from the oldest to the newest
+    async fn scan(path: &Path) -> Result<Vec<SpoolEntry>, BatchSenderError> {
+        let mut dir = tokio::fs::read_dir(path)
+            .await
+            .map_err(|e| BatchSenderError::SpoolError(e.to_string()))?;
+        let mut entries = Vec::new();
+        while let Some(file) = dir
+            .next_entry()
+            .await
+            .map_err(|e| BatchSenderError::SpoolError(e.to_string()))?
+        {
+            let path = file.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(Self::EXTENSION) {
+                continue;
+            }
+            let Some(timestamp) = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok())
+            else {
+                continue;
+            };
+            let size = file
+                .metadata()
+                .await
+                .map_err(|e| BatchSenderError::SpoolError(e.to_string()))?
+                .len();
+            entries.push(SpoolEntry {
+                path,
+                spooled_at: UNIX_EPOCH + Duration::from_nanos(timestamp),
+                size,
+            });
+        }
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(entries)
+    }
+
+    /// Drop batches older than the maximum age, then the oldest batches until
+    /// the spool fits in its maximum size
+    async fn prune(&self, index: &mut SpoolIndex) -> Result<(), BatchSenderError> {
+        let max_age = Duration::from_secs(self.config.max_age);
+        while let Some(oldest) = index.entries.front() {
+            if oldest.spooled_at.elapsed().unwrap_or_default() > max_age {
+                tracing::warn!(path = ?oldest.path, "Dropping spooled batch, it exceeded the maximum age");
+            } else if index.size > self.config.max_size {
+                tracing::warn!(path = ?oldest.path, "Dropping spooled batch, the spool exceeded its maximum size");
+            } else {
+                break;
+            }
+            if let Some(entry) = index.pop_front() {
+                Self::remove_file(&entry).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Nanoseconds elapsed since the Unix epoch, naming spooled batches
+fn nanos_since_epoch(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use isok_data::broker_rpc::CheckResult;
+    use pretty_assertions::assert_eq;
+
+    fn batch(id: &str) -> CheckBatchRequest {
+        CheckBatchRequest {
+            tags: None,
+            created_at: None,
+            events: vec![CheckResult {
+                id_ulid: id.to_string(),
+                ..Default::default()
+            }],
+        }
+    }
+
+    fn config(path: PathBuf) -> SpoolConfig {
+        SpoolConfig {
+            path,
+            max_size: 1024 * 1024,
+            max_age: 3600,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_spool_order_and_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = Spool::open(config(dir.path().to_path_buf())).await.unwrap();
+        for id in ["first", "second", "third"] {
+            spool.push(&batch(id)).await.unwrap();
+        }
+
+        // Reopening the spool keeps the spooled batches
+        let spool = Spool::open(config(dir.path().to_path_buf())).await.unwrap();
+        let mut ids = vec![];
+        while let Some((entry, batch)) = spool.peek().await.unwrap() {
+            ids.push(batch.events[0].id_ulid.clone());
+            spool.remove(entry).await.unwrap();
+        }
+        assert_eq!(ids, vec!["first", "second", "third"]);
+        assert!(spool.peek().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_spool_max_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let batch_size = batch("first").encoded_len() as u64;
+        let spool = Spool::open(SpoolConfig {
+            max_size: batch_size * 2,
+            ..config(dir.path().to_path_buf())
+        })
+        .await
+        .unwrap();
+        for id in ["first", "secnd", "third"] {
+            spool.push(&batch(id)).await.unwrap();
+        }
+
+        let (_, oldest) = spool.peek().await.unwrap().unwrap();
+        assert_eq!(oldest.events[0].id_ulid, "secnd");
+        assert_eq!(Spool::scan(dir.path()).await.unwrap().len(), 2);
+        assert_eq!(spool.index.lock().await.size, batch_size * 2);
+
+        // The running size follows removed batches
+        let (entry, _) = spool.peek().await.unwrap().unwrap();
+        spool.remove(entry).await.unwrap();
+        spool.push(&batch("forth")).await.unwrap();
+        assert_eq!(Spool::scan(dir.path()).await.unwrap().len(), 2);
+        assert_eq!(spool.index.lock().await.size, batch_size * 2);
+    }
+
+    #[tokio::test]
+    async fn test_spool_max_age() {
+        let dir = tempfile::tempdir().unwrap();
+        let spool = Spool::open(config(dir.path().to_path_buf())).await.unwrap();
+        spool.push(&batch("expired")).await.unwrap();
+
+        let spool = Spool::open(SpoolConfig {
+            max_age: 0,
+            ..config(dir.path().to_path_buf())
+        })
+        .await
+        .unwrap();
+        assert!(spool.peek().await.unwrap().is_none());
+    }
+}
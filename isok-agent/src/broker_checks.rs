@@ -0,0 +1,181 @@
+use crate::batch_sender::BrokerBatchSender;
+use crate::config::BrokerConfigCheckAdapter;
+use crate::errors::{Error, Result};
+use crate::jobs::template::{CheckDefinitions, Interpolation};
+use crate::jobs::Job;
+use crate::registry::JobRegistry;
+use isok_data::broker_rpc::{BrokerGrpcClient, GetChecksRequest, GetChecksResponse, Tags};
+use std::time::Duration;
+use tonic::metadata::{Ascii, MetadataValue};
+
+/// Checks pulled from brokers, selected by the tags of the agent
+pub(crate) struct BrokerChecks {
+    /// Brokers by order of priority, the main broker being the first one
+    brokers: Vec<(String, BrokerGrpcClient)>,
+    authorization: Option<MetadataValue<Ascii>>,
+    tags: Tags,
+    poll_interval: Duration,
+    /// Version of the last pulled checks, along with the checks
+    current: Option<(String, Vec<Job>)>,
+}
+
+impl BrokerChecks {
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+    pub(crate) async fn new(config: &BrokerConfigCheckAdapter) -> Result<Self> {
+        let addresses: Vec<_> = std::iter::once(&config.main_broker)
+            .chain(config.fallback_brokers.iter())
+            .collect();
+        let clients = BrokerBatchSender::connect_lazy(&addresses, config.tls.as_ref())
+            .await
+            .map_err(Error::UnableToConnectCheckBrokers)?;
+        let authorization = BrokerBatchSender::authorization(config.token.as_deref())
+            .map_err(Error::UnableToConnectCheckBrokers)?;
+
+        Ok(BrokerChecks {
+            brokers: addresses.into_iter().cloned().zip(clients).collect(),
+            authorization,
+            tags: Tags {
+                agent_id: config.agent_id.clone(),
+                zone: config.zone.clone(),
+                region: config.region.clone(),
+            },
+            poll_interval: Duration::from_secs(config.poll_interval.max(1)),
+            current: None,
+        })
+    }
+
+    pub(crate) fn poll_interval(&self) -> Duration {
+        self.poll_interval
+    }
+
+    /// Pull the checks of the agent from the first broker answering
+    pub(crate) async fn pull(&mut self) -> Result<Vec<Job>> {
+        let mut errors = vec![];
+        for (address, client) in &mut self.brokers {
+            let mut request = tonic::Request::new(GetChecksRequest {
+                tags: Some(self.tags.clone()),
+                version: self.current.as_ref().map(|(version, _)| version.clone()),
+            });
+            request.set_timeout(Self::REQUEST_TIMEOUT);
+            if let Some(authorization) = &self.authorization {
+                request
+                    .metadata_mut()
+                    .insert("authorization", authorization.clone());
+            }
+
+            match client.get_checks(request).await {
+                Ok(response) => return self.apply(response.into_inner()),
+                Err(status) => {
+                    tracing::debug!("Unable to pull checks from {}: {}", address, status);
+                    errors.push(format!("{}: {}", address, status.message()));
+                }
+            }
+        }
+        Err(Error::UnableToPullChecks(errors.join(", ")))
+    }
+
+    fn apply(&mut self, response: GetChecksResponse) -> Result<Vec<Job>> {
+        if let Some((version, jobs)) = &self.current {
+            if response.unchanged && response.version == *version {
+                return Ok(jobs.clone());
+            }
+        }
+
+        // Checks are resolved and validated like local ones, without reading the
+        // agent environment, before replacing the current version
+        let checks = response
+            .checks
+            .iter()
+            .map(|check| serde_json::from_str(check))
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(Error::InvalidBrokerCheck)?;
+        let jobs = CheckDefinitions::from(checks).resolve(Interpolation::Disabled)?;
+        JobRegistry::validate(&jobs)?;
+        self.current = Some((response.version, jobs.clone()));
+        Ok(jobs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jobs::template::TemplateError;
+    use pretty_assertions::assert_eq;
+
+    async fn broker_checks() -> BrokerChecks {
+        let config: BrokerConfigCheckAdapter = serde_yaml::from_str(
+            r#"
+            main_broker: "http://127.0.0.1:50551"
+            agent_id: "test"
+            zone: "dev"
+            region: "localhost"
+            "#,
+        )
+        .unwrap();
+        BrokerChecks::new(&config).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_apply_pulled_checks() {
+        let mut broker_checks = broker_checks().await;
+        let check = r#"{"id": "01ARZ3NDEKTSV4RRWETS2EGZ5M", "type": "tcp", "pretty_name": "tcp", "endpoint": "127.0.0.1:8080", "secured": false, "interval": 10}"#;
+        let jobs = broker_checks
+            .apply(GetChecksResponse {
+                version: "1".to_string(),
+                unchanged: false,
+                checks: vec![check.to_string()],
+            })
+            .unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert_eq!(jobs[0].id().to_string(), "01ARZ3NDEKTSV4RRWETS2EGZ5M");
+
+        // Unchanged checks aren't sent again
+        let unchanged = broker_checks
+            .apply(GetChecksResponse {
+                version: "1".to_string(),
+                unchanged: true,
+                checks: vec![],
+            })
+            .unwrap();
+        assert_eq!(unchanged, jobs);
+
+        // An invalid check rejects the whole set, the previous version being kept
+        let mut apply = |checks: &[&str]| {
+            broker_checks.apply(GetChecksResponse {
+                version: "2".to_string(),
+                unchanged: false,
+                checks: checks.iter().map(|check| check.to_string()).collect(),
+            })
+        };
+        assert!(matches!(
+            apply(&["not json"]),
+            Err(Error::InvalidBrokerCheck(_))
+        ));
+        assert!(matches!(
+            apply(&[r#"{"type": "ftp"}"#]),
+            Err(Error::InvalidCheckTemplate(
+                TemplateError::InvalidCheck { .. }
+            ))
+        ));
+        let too_frequent = r#"{"type": "tcp", "pretty_name": "tcp", "endpoint": "127.0.0.1:8080", "secured": false, "interval": "10ms"}"#;
+        assert!(matches!(
+            apply(&[too_frequent]),
+            Err(Error::InvalidCheckTemplate(
+                TemplateError::InvalidCheck { .. }
+            ))
+        ));
+        assert!(matches!(
+            apply(&[check, check]),
+            Err(Error::InvalidCheckTemplate(
+                TemplateError::DuplicateId { .. }
+            ))
+        ));
+        let invalid_header = r#"{"type": "http", "pretty_name": "http", "endpoint": "http://127.0.0.1:8080", "interval": 10, "headers": {"invalid header": "value"}}"#;
+        assert!(matches!(
+            apply(&[invalid_header]),
+            Err(Error::InvalidCheck { .. })
+        ));
+        assert_eq!(broker_checks.current.unwrap(), ("1".to_string(), jobs));
+    }
+}
@@ -0,0 +1,393 @@
+//! Checks files, merged from single files, directories and glob patterns.
+//!
+//! Each file holds its own `checks` list, in YAML, JSON or TOML depending on its
+//! extension. Files of a directory are loaded by name order, those with another
+//! extension being ignored.
+
+use crate::errors::{Error, Result};
+use crate::jobs::template::Interpolation;
+use crate::jobs::Job;
+use crate::registry::JobRegistry;
+use figment::providers::{Format, Json, Toml, Yaml};
+use glob::Pattern;
+use isok_data::JobId;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Extensions of the files loaded from directories
+const EXTENSIONS: [&str; 4] = ["yaml", "yml", "json", "toml"];
+
+fn is_glob(path: &str) -> bool {
+    path.contains(['*', '?', '['])
+}
+
+fn has_checks_extension(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| EXTENSIONS.contains(&extension))
+}
+
+/// Where checks files are looked for
+#[derive(Debug)]
+enum ChecksPath {
+    File(PathBuf),
+    /// Every checks file of the directory, not recursively
+    Directory(PathBuf),
+    /// Files matching the pattern, `base` being the directory the pattern starts from
+    Glob {
+        pattern: Pattern,
+        base: PathBuf,
+    },
+}
+
+impl ChecksPath {
+    fn parse(path: &str) -> Result<Self> {
+        if is_glob(path) {
+            let pattern = Pattern::new(path)
+                .map_err(|e| Error::InvalidChecksPath(format!("{}: {}", path, e)))?;
+            let base: PathBuf = Path::new(path)
+                .components()
+                .take_while(|component| !is_glob(&component.as_os_str().to_string_lossy()))
+                .collect();
+            return Ok(ChecksPath::Glob { pattern, base });
+        }
+        let path = PathBuf::from(path);
+        Ok(if path.is_dir() {
+            ChecksPath::Directory(path)
+        } else {
+            ChecksPath::File(path)
+        })
+    }
+
+    fn files(&self) -> Result<Vec<PathBuf>> {
+        let mut files = match self {
+            ChecksPath::File(file) => return Ok(vec![file.clone()]),
+            ChecksPath::Directory(directory) => std::fs::read_dir(directory)
+                .map_err(|e| Error::InvalidChecksPath(format!("{}: {}", directory.display(), e)))?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.is_file() && has_checks_extension(path))
+                .collect::<Vec<_>>(),
+            ChecksPath::Glob { pattern, .. } => glob::glob(pattern.as_str())
+                .map_err(|e| Error::InvalidChecksPath(format!("{}: {}", pattern, e)))?
+                .filter_map(|path| {
+                    path.map_err(|e| tracing::warn!("Unable to read checks path: {}", e))
+                        .ok()
+                })
+                .filter(|path| path.is_file())
+                .collect(),
+        };
+        files.sort();
+        Ok(files)
+    }
+
+    /// Whether a change of `path` may change the checks
+    fn is_affected_by(&self, path: &Path) -> bool {
+        match self {
+            ChecksPath::File(file) => match (resolved(path), resolved(file)) {
+                (Some(path), Some(file)) => path == file,
+                _ => false,
+            },
+            ChecksPath::Directory(_) => has_checks_extension(path),
+            ChecksPath::Glob { pattern, .. } => {
+                // Paths of events under the current directory start with `./`
+                let relative: PathBuf = path
+                    .components()
+                    .skip_while(|component| *component == Component::CurDir)
+                    .collect();
+                pattern.matches_path(path) || pattern.matches_path(&relative)
+            }
+        }
+    }
+
+    /// Directory to watch, and whether to watch it recursively
+    fn watched_directory(&self) -> (PathBuf, RecursiveMode) {
+        let or_current = |directory: &Path| match directory.as_os_str().is_empty() {
+            true => PathBuf::from("."),
+            false => directory.to_path_buf(),
+        };
+        match self {
+            // Editors usually replace the file rather than writing it in place,
+            // watching the parent directory catches both
+            ChecksPath::File(file) => (
+                or_current(file.parent().unwrap_or(Path::new(""))),
+                RecursiveMode::NonRecursive,
+            ),
+            ChecksPath::Directory(directory) => (directory.clone(), RecursiveMode::NonRecursive),
+            ChecksPath::Glob { pattern, base } => {
+                let nested = Path::new(pattern.as_str())
+                    .strip_prefix(base)
+                    .is_ok_and(|rest| rest.components().count() > 1);
+                let mode = match nested {
+                    true => RecursiveMode::Recursive,
+                    false => RecursiveMode::NonRecursive,
+                };
+                (or_current(base), mode)
+            }
+        }
+    }
+}
+
+/// Absolute path of `path`, only its parent directory being canonicalized as the
+/// file itself may have been removed
+fn resolved(path: &Path) -> Option<PathBuf> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    Some(parent.canonicalize().ok()?.join(path.file_name()?))
+}
+
+fn read_file(file: &Path) -> Result<Vec<Job>> {
+    match file.extension().and_then(|extension| extension.to_str()) {
+        Some("json") => JobRegistry::read_configuration(Json::file(file), Interpolation::Enabled),
+        Some("toml") => JobRegistry::read_configuration(Toml::file(file), Interpolation::Enabled),
+        _ => JobRegistry::read_configuration(Yaml::file(file), Interpolation::Enabled),
+    }
+}
+
+/// Load and merge the checks of every file of `paths`. A check ID or name defined
+/// twice is rejected.
+pub(crate) fn load(paths: &[String]) -> Result<Vec<Job>> {
+    let mut loaded = HashSet::new();
+    let mut ids: HashMap<JobId, PathBuf> = HashMap::new();
+    let mut names: HashMap<String, PathBuf> = HashMap::new();
+    let mut jobs = vec![];
+
+    for path in paths {
+        let files = ChecksPath::parse(path)?.files()?;
+        if files.is_empty() {
+            tracing::warn!("No checks file found in {}", path);
+        }
+        for file in files {
+            // Files matched by several paths are only loaded once
+            if !loaded.insert(file.clone()) {
+                continue;
+            }
+            for job in read_file(&file)? {
+                let duplicate = |kind, value: String, first: PathBuf| Error::DuplicateCheck {
+                    kind,
+                    value,
+                    first,
+                    second: file.clone(),
+                };
+                if let Some(first) = ids.insert(job.id(), file.clone()) {
+                    return Err(duplicate("ID", job.id().to_string(), first));
+                }
+                if let Some(first) = names.insert(job.pretty_name(), file.clone()) {
+                    return Err(duplicate("name", job.pretty_name(), first));
+                }
+                jobs.push(job.with_source(file.clone()));
+            }
+        }
+    }
+    Ok(jobs)
+}
+
+/// Notify `tx` whenever a checks file of `paths` changes, as long as the returned
+/// watcher is alive
+pub(crate) fn watch(
+    paths: &[String],
+    tx: UnboundedSender<()>,
+) -> notify::Result<RecommendedWatcher> {
+    let paths = paths
+        .iter()
+        .map(|path| ChecksPath::parse(path))
+        .collect::<Result<Vec<_>>>()
+        .map_err(|e| notify::Error::generic(&e.to_string()))?;
+    let directories: Vec<_> = paths.iter().map(ChecksPath::watched_directory).collect();
+
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+            Ok(event)
+                if matches!(
+                    event.kind,
+                    EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+                ) && event
+                    .paths
+                    .iter()
+                    .any(|path| paths.iter().any(|checks| checks.is_affected_by(path))) =>
+            {
+                let _ = tx.send(());
+            }
+            Ok(_) => {}
+            Err(e) => tracing::warn!("Error while watching checks files: {}", e),
+        })?;
+    for (directory, mode) in directories {
+        watcher.watch(&directory, mode)?;
+    }
+    Ok(watcher)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn write(directory: &Path, name: &str, content: &str) -> PathBuf {
+        let path = directory.join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn tcp_check(id: &str, name: &str) -> String {
+        format!(
+            r#"{{ "id": "{}", "type": "tcp", "pretty_name": "{}", "endpoint": "127.0.0.1:8080", "secured": false, "interval": 10 }}"#,
+            id, name
+        )
+    }
+
+    #[test]
+    fn test_load_merged_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let payments = write(
+            dir.path(),
+            "teams/payments.yaml",
+            &format!(
+                "checks:\n  - {}",
+                tcp_check("01ARZ3NDEKTSV4RRWETS2EGZ5A", "payments")
+            ),
+        );
+        let search = write(
+            dir.path(),
+            "teams/search.json",
+            &format!(
+                r#"{{ "checks": [{}] }}"#,
+                tcp_check("01ARZ3NDEKTSV4RRWETS2EGZ5B", "search")
+            ),
+        );
+        let billing = write(
+            dir.path(),
+            "teams/billing.toml",
+            r#"
+            [[checks]]
+            id = "01ARZ3NDEKTSV4RRWETS2EGZ5C"
+            type = "tcp"
+            pretty_name = "billing"
+            endpoint = "127.0.0.1:8080"
+            secured = false
+            interval = 10
+            "#,
+        );
+        // Ignored, not a checks file
+        write(dir.path(), "teams/README.md", "# Checks");
+        let shared = write(
+            dir.path(),
+            "shared/common.yml",
+            &format!(
+                "checks:\n  - {}",
+                tcp_check("01ARZ3NDEKTSV4RRWETS2EGZ5D", "common")
+            ),
+        );
+
+        let paths = [
+            dir.path().join("teams").display().to_string(),
+            format!("{}/shared/*.yml", dir.path().display()),
+            // Already loaded
+            payments.display().to_string(),
+        ];
+        let sources: Vec<_> = load(&paths)
+            .unwrap()
+            .iter()
+            .map(|job| (job.pretty_name(), job.source().unwrap().to_path_buf()))
+            .collect();
+        assert_eq!(
+            sources,
+            [
+                ("billing".to_string(), billing),
+                ("payments".to_string(), payments),
+                ("search".to_string(), search),
+                ("common".to_string(), shared),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_duplicates_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = write(
+            dir.path(),
+            "a.yaml",
+            &format!(
+                "checks:\n  - {}",
+                tcp_check("01ARZ3NDEKTSV4RRWETS2EGZ5A", "payments")
+            ),
+        );
+        let second = write(
+            dir.path(),
+            "b.yaml",
+            &format!(
+                "checks:\n  - {}",
+                tcp_check("01ARZ3NDEKTSV4RRWETS2EGZ5B", "payments")
+            ),
+        );
+        let paths = [dir.path().display().to_string()];
+        let Err(Error::DuplicateCheck {
+            kind,
+            value,
+            first: first_file,
+            second: second_file,
+        }) = load(&paths)
+        else {
+            panic!("Expected the duplicate name to be rejected");
+        };
+        assert_eq!((kind, value.as_str()), ("name", "payments"));
+        assert_eq!((first_file, second_file), (first, second.clone()));
+
+        write(
+            dir.path(),
+            "b.yaml",
+            &format!(
+                "checks:\n  - {}",
+                tcp_check("01ARZ3NDEKTSV4RRWETS2EGZ5A", "search")
+            ),
+        );
+        let error = load(&paths).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Duplicate check ID 01ARZ3NDEKTSV4RRWETS2EGZ5A, defined in {} and {}",
+                dir.path().join("a.yaml").display(),
+                second.display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_file_affected_by() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = write(dir.path(), "checks.yaml", "checks: []");
+        let other = write(&dir.path().join("other"), "checks.yaml", "checks: []");
+        let checks = ChecksPath::parse(&file.display().to_string()).unwrap();
+
+        assert!(checks.is_affected_by(&file));
+        assert!(checks.is_affected_by(&dir.path().join("other/../checks.yaml")));
+        // Files sharing the name in another directory are unrelated
+        assert!(!checks.is_affected_by(&other));
+        // The file can be replaced, the event being reported once it's removed
+        std::fs::remove_file(&file).unwrap();
+        assert!(checks.is_affected_by(&file));
+    }
+
+    #[tokio::test]
+    async fn test_watch_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, mut changes) = tokio::sync::mpsc::unbounded_channel();
+        let _watcher = watch(&[dir.path().display().to_string()], tx).unwrap();
+
+        write(dir.path(), "notes.txt", "");
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(500), changes.recv())
+                .await
+                .is_err()
+        );
+
+        write(dir.path(), "team.yaml", "checks: []");
+        tokio::time::timeout(std::time::Duration::from_secs(2), changes.recv())
+            .await
+            .expect("Expected the new file to be notified")
+            .unwrap();
+    }
+}
@@ -1,15 +1,24 @@
+use crate::check_files;
 use crate::errors::{Error, Result};
+use crate::jobs::duration;
+use crate::jobs::template::{CheckDefinitions, Interpolation, TemplateError};
 use crate::jobs::Job;
 use crate::registry::JobRegistry;
 use figment::providers::{Format, Yaml};
 use figment::Figment;
+use notify::RecommendedWatcher;
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
 
 #[derive(Debug, Deserialize, PartialEq)]
 pub struct Config {
     pub check_config_adapter: ConfigCheckAdapter,
     pub result_sender_adapter: ResultSenderAdapter,
+    #[serde(default)]
+    pub scheduler: SchedulerConfig,
 }
 
 impl GetJobsRegistry for Config {
@@ -37,6 +46,42 @@ impl Default for Config {
                 checks: vec![],
             }),
             result_sender_adapter: ResultSenderAdapter::Stdout,
+            scheduler: SchedulerConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+pub struct SchedulerConfig {
+    /// Maximum number of checks running at the same time, any other due
+    /// check waits for a running one to complete.
+    #[serde(default = "SchedulerConfig::default_max_concurrent_jobs")]
+    pub max_concurrent_jobs: usize,
+    /// Timeout applied to checks that don't define their own, e.g. `1500ms` or a
+    /// number of seconds. A check running for longer is cancelled and reported as
+    /// timed out.
+    #[serde(
+        default = "SchedulerConfig::default_timeout",
+        deserialize_with = "duration::timeout::deserialize"
+    )]
+    pub default_timeout: Duration,
+}
+
+impl SchedulerConfig {
+    fn default_max_concurrent_jobs() -> usize {
+        256
+    }
+
+    fn default_timeout() -> Duration {
+        Duration::from_secs(10)
+    }
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        SchedulerConfig {
+            max_concurrent_jobs: Self::default_max_concurrent_jobs(),
+            default_timeout: Self::default_timeout(),
         }
     }
 }
@@ -47,6 +92,7 @@ pub trait GetJobsRegistry {
 
 #[derive(Debug, Deserialize, PartialEq)]
 #[serde(tag = "type")]
+#[allow(clippy::large_enum_variant)]
 pub enum ResultSenderAdapter {
     #[serde(rename = "stdout")]
     Stdout,
@@ -65,21 +111,88 @@ pub struct SocketConfig {
 #[derive(Debug, Deserialize, PartialEq)]
 pub struct BrokerConfig {
     pub main_broker: String,
+    /// Brokers used when the main one fails, by order of priority
     pub fallback_brokers: Vec<String>,
     pub agent_id: String,
     pub zone: String,
     pub region: String,
     pub batch: u64,
     pub batch_interval: u64,
+    /// Interval in seconds at which brokers health is probed, to switch to a
+    /// fallback broker or back to the main one
+    #[serde(default = "BrokerConfig::default_health_check_interval")]
+    pub health_check_interval: u64,
+    /// Store batches that couldn't be delivered on disk, to replay them once
+    /// a broker is reachable again
+    #[serde(default)]
+    pub spool: Option<SpoolConfig>,
+    /// Biscuit token generated with `isok-cli new-agent`, sent to the brokers to
+    /// authenticate the agent
+    #[serde(default)]
+    pub token: Option<String>,
+    /// TLS settings of the connection to the brokers. Brokers with an `https` address
+    /// are verified against the system roots when not set.
+    #[serde(default)]
+    pub tls: Option<BrokerTlsConfig>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Clone, Default)]
+pub struct BrokerTlsConfig {
+    /// PEM bundle of the certificate authorities trusted to sign the brokers certificate,
+    /// the system roots are used when not set
+    #[serde(default)]
+    pub ca_cert: Option<PathBuf>,
+    /// PEM certificate presented to brokers requiring mutual TLS
+    #[serde(default)]
+    pub client_cert: Option<PathBuf>,
+    /// PEM private key of the client certificate
+    #[serde(default)]
+    pub client_key: Option<PathBuf>,
+    /// Name expected in the brokers certificate, the host of the broker address by default
+    #[serde(default)]
+    pub domain_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Clone)]
+pub struct SpoolConfig {
+    /// Directory in which undelivered batches are stored
+    pub path: PathBuf,
+    /// Maximum size in bytes of the spool, the oldest batches are dropped beyond it
+    #[serde(default = "SpoolConfig::default_max_size")]
+    pub max_size: u64,
+    /// Maximum age in seconds of a spooled batch, older batches are dropped
+    #[serde(default = "SpoolConfig::default_max_age")]
+    pub max_age: u64,
+}
+
+impl SpoolConfig {
+    fn default_max_size() -> u64 {
+        100 * 1024 * 1024
+    }
+
+    fn default_max_age() -> u64 {
+        7 * 24 * 3600
+    }
+}
+
+impl BrokerConfig {
+    fn default_health_check_interval() -> u64 {
+        10
+    }
 }
 
 #[derive(Debug, Deserialize, PartialEq)]
 #[serde(tag = "name")]
+#[allow(clippy::large_enum_variant)]
 pub enum ConfigCheckAdapter {
     #[serde(rename = "static")]
     Static(StaticConfigAdapter),
     #[serde(rename = "file")]
     File(FileConfigCheckAdapter),
+    #[serde(rename = "broker")]
+    Broker(BrokerConfigCheckAdapter),
+    #[serde(rename = "http")]
+    Http(HttpConfigCheckAdapter),
 }
 
 impl GetJobsRegistry for ConfigCheckAdapter {
@@ -87,23 +200,114 @@ impl GetJobsRegistry for ConfigCheckAdapter {
         match self {
             ConfigCheckAdapter::File(adapter) => adapter.get_jobs_registry(),
             ConfigCheckAdapter::Static(adapter) => adapter.get_jobs_registry(),
+            // The agent starts without checks, until they're pulled
+            ConfigCheckAdapter::Broker(_) | ConfigCheckAdapter::Http(_) => {
+                JobRegistry::from_static_config(vec![])
+            }
         }
     }
 }
 
 #[derive(Debug, Deserialize, PartialEq)]
 pub struct FileConfigCheckAdapter {
+    /// Checks file, directory of checks files or glob pattern matching them
     path: String,
+    /// Other files, directories or glob patterns checks are merged from
+    #[serde(default)]
+    include: Vec<String>,
 }
 
+/// Checks of the agent configuration, which can declare `defaults` and `templates`
+/// like checks files
 #[derive(Debug, Deserialize, PartialEq)]
+#[serde(try_from = "CheckDefinitions")]
 pub struct StaticConfigAdapter {
     pub checks: Vec<Job>,
 }
 
+impl TryFrom<CheckDefinitions> for StaticConfigAdapter {
+    type Error = TemplateError;
+
+    fn try_from(definitions: CheckDefinitions) -> std::result::Result<Self, Self::Error> {
+        Ok(StaticConfigAdapter {
+            checks: definitions.resolve(Interpolation::Enabled)?,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct BrokerConfigCheckAdapter {
+    pub main_broker: String,
+    /// Brokers checks are pulled from when the main one fails, by order of priority
+    #[serde(default)]
+    pub fallback_brokers: Vec<String>,
+    /// Tags the brokers select the checks of the agent with
+    pub agent_id: String,
+    pub zone: String,
+    pub region: String,
+    /// Interval in seconds at which checks are pulled from the brokers
+    #[serde(default = "BrokerConfigCheckAdapter::default_poll_interval")]
+    pub poll_interval: u64,
+    /// Biscuit token generated with `isok-cli new-agent`, sent to the brokers to
+    /// authenticate the agent
+    #[serde(default)]
+    pub token: Option<String>,
+    /// TLS settings of the connection to the brokers. Brokers with an `https` address
+    /// are verified against the system roots when not set.
+    #[serde(default)]
+    pub tls: Option<BrokerTlsConfig>,
+}
+
+impl BrokerConfigCheckAdapter {
+    fn default_poll_interval() -> u64 {
+        30
+    }
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+pub struct HttpConfigCheckAdapter {
+    /// URL of a checks document, in the format of the checks file. It's parsed as JSON
+    /// when served with a JSON content type, as YAML otherwise.
+    pub url: String,
+    /// Interval in seconds at which the document is fetched
+    #[serde(default = "HttpConfigCheckAdapter::default_poll_interval")]
+    pub poll_interval: u64,
+    /// Headers sent along every request, e.g. to authenticate the agent
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// File the last valid document is stored in, the checks are loaded from it when
+    /// the agent starts while the URL is unreachable
+    #[serde(default)]
+    pub cache_path: Option<PathBuf>,
+}
+
+impl HttpConfigCheckAdapter {
+    fn default_poll_interval() -> u64 {
+        60
+    }
+}
+
+impl FileConfigCheckAdapter {
+    fn paths(&self) -> Vec<String> {
+        std::iter::once(&self.path)
+            .chain(self.include.iter())
+            .cloned()
+            .collect()
+    }
+
+    pub(crate) fn load_checks(&self) -> Result<Vec<Job>> {
+        check_files::load(&self.paths())
+    }
+
+    /// Notify `tx` whenever a checks file changes, as long as the returned watcher is alive
+    pub(crate) fn watch(&self, tx: UnboundedSender<()>) -> notify::Result<RecommendedWatcher> {
+        check_files::watch(&self.paths(), tx)
+    }
+}
+
 impl GetJobsRegistry for FileConfigCheckAdapter {
     fn get_jobs_registry(&self) -> Result<JobRegistry> {
-        JobRegistry::from_configuration_file(PathBuf::from(&self.path))
+        JobRegistry::from_static_config(self.load_checks()?)
     }
 }
 
@@ -119,9 +323,41 @@ mod tests {
 
     #[test]
     fn test_asset_example_config() {
-        let _ = Config::from_config_file(
+        // The token referenced by the example checks is read from a file, as the
+        // environment is shared by the tests
+        let dir = tempfile::tempdir().unwrap();
+        let token = dir.path().join("api_token");
+        std::fs::write(&token, "my-token\n").unwrap();
+        let example = std::fs::read_to_string(
             env!("CARGO_MANIFEST_DIR").to_string() + "/assets/config/agent.example.yaml",
         )
-        .expect("Unable to load default config");
+        .unwrap();
+        let config = dir.path().join("agent.yaml");
+        std::fs::write(
+            &config,
+            example.replace(
+                "${ISOK_API_TOKEN}",
+                &format!("${{file:{}}}", token.display()),
+            ),
+        )
+        .unwrap();
+        let _ = Config::from_config_file(config).expect("Unable to load default config");
+    }
+
+    #[test]
+    fn test_scheduler_default_timeout() {
+        let scheduler = |config: &str| serde_yaml::from_str::<SchedulerConfig>(config);
+        assert_eq!(
+            scheduler("default_timeout: 1500ms")
+                .unwrap()
+                .default_timeout,
+            Duration::from_millis(1500)
+        );
+        assert_eq!(
+            scheduler("default_timeout: 5").unwrap().default_timeout,
+            Duration::from_secs(5)
+        );
+        assert_eq!(scheduler("{}").unwrap(), SchedulerConfig::default());
+        assert!(scheduler("default_timeout: 0s").is_err());
     }
 }
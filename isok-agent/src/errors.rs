@@ -1,4 +1,7 @@
 use crate::batch_sender::BatchSenderError;
+use crate::jobs::template::TemplateError;
+use crate::jobs::JobError;
+use std::path::PathBuf;
 use thiserror::Error;
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -6,9 +9,44 @@ pub type Result<T> = std::result::Result<T, Error>;
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Unable to create job registry")]
-    UnableToCreateJobRegistry(#[from] figment::Error),
+    UnableToCreateJobRegistry(#[source] Box<figment::Error>),
     #[error("Config path provided is invalid")]
     InvalidConfigPath,
+    #[error("Check {pretty_name} is invalid{}", .file.as_ref().map(|file| format!(" ({})", file.display())).unwrap_or_default())]
+    InvalidCheck {
+        pretty_name: String,
+        /// File the check was loaded from
+        file: Option<PathBuf>,
+        #[source]
+        source: JobError,
+    },
     #[error("Unable to create batch sender")]
     UnableToCreateBatchSender(#[from] BatchSenderError),
+    #[error("Unable to connect to the brokers serving checks")]
+    UnableToConnectCheckBrokers(#[source] BatchSenderError),
+    #[error("Unable to pull checks from brokers: {0}")]
+    UnableToPullChecks(String),
+    #[error("Invalid check received from broker")]
+    InvalidBrokerCheck(#[source] serde_json::Error),
+    #[error("Unable to resolve check templates: {0}")]
+    InvalidCheckTemplate(#[from] TemplateError),
+    #[error("Invalid checks path: {0}")]
+    InvalidChecksPath(String),
+    #[error("Duplicate check {kind} {value}, defined in {} and {}", .first.display(), .second.display())]
+    DuplicateCheck {
+        kind: &'static str,
+        value: String,
+        first: PathBuf,
+        second: PathBuf,
+    },
+    #[error("Invalid checks document source: {0}")]
+    InvalidChecksSource(String),
+    #[error("Unable to fetch checks: {0}")]
+    UnableToFetchChecks(#[from] reqwest::Error),
+}
+
+impl From<figment::Error> for Error {
+    fn from(value: figment::Error) -> Self {
+        Error::UnableToCreateJobRegistry(Box::new(value))
+    }
 }
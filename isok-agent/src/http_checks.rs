@@ -0,0 +1,342 @@
+use crate::config::HttpConfigCheckAdapter;
+use crate::errors::{Error, Result};
+use crate::jobs::template::Interpolation;
+use crate::jobs::{tls, Job};
+use crate::registry::JobRegistry;
+use figment::providers::{Format, Json, Yaml};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+use reqwest::{StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Checks document, as served by the remote source
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+struct Document {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    etag: Option<String>,
+    /// Whether the document was served as JSON, YAML otherwise
+    json: bool,
+    body: String,
+}
+
+impl Document {
+    /// Checks of the document, which is only valid when every check is
+    fn checks(&self) -> Result<Vec<Job>> {
+        let jobs = if self.json {
+            JobRegistry::read_configuration(Json::string(&self.body), Interpolation::Disabled)?
+        } else {
+            JobRegistry::read_configuration(Yaml::string(&self.body), Interpolation::Disabled)?
+        };
+        JobRegistry::validate(&jobs)?;
+        Ok(jobs)
+    }
+}
+
+/// Checks fetched from a remote document on an interval
+pub(crate) struct HttpChecks {
+    client: reqwest::Client,
+    url: Url,
+    poll_interval: Duration,
+    cache_path: Option<PathBuf>,
+    /// Last valid document, along with its checks
+    current: Option<(Document, Vec<Job>)>,
+}
+
+impl HttpChecks {
+    const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+    pub(crate) fn new(config: &HttpConfigCheckAdapter) -> Result<Self> {
+        let invalid = Error::InvalidChecksSource;
+        let url = Url::parse(&config.url)
+            .map_err(|e| invalid(format!("Invalid URL {}: {}", config.url, e)))?;
+        let headers = config
+            .headers
+            .iter()
+            .map(|(name, value)| {
+                Ok((
+                    HeaderName::from_str(name)
+                        .map_err(|_| invalid(format!("Invalid header name {}", name)))?,
+                    HeaderValue::from_str(value)
+                        .map_err(|_| invalid(format!("Invalid value of header {}", name)))?,
+                ))
+            })
+            .collect::<Result<HeaderMap>>()?;
+        let roots = tls::system_roots().map_err(invalid)?;
+
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .timeout(Self::REQUEST_TIMEOUT)
+            .use_preconfigured_tls(tls::client_config(roots).map_err(invalid)?)
+            .build()
+            .map_err(|e| invalid(e.to_string()))?;
+        Ok(HttpChecks {
+            client,
+            url,
+            poll_interval: Duration::from_secs(config.poll_interval.max(1)),
+            cache_path: config.cache_path.clone(),
+            current: None,
+        })
+    }
+
+    pub(crate) fn poll_interval(&self) -> Duration {
+        self.poll_interval
+    }
+
+    /// Fetch the checks, falling back on the cached copy until a document is fetched
+    pub(crate) async fn pull(&mut self) -> Result<Vec<Job>> {
+        match self.fetch().await {
+            Err(e) if self.current.is_none() => {
+                let Some(document) = self.read_cache().await else {
+                    return Err(e);
+                };
+                tracing::warn!(error = ?e, "Unable to fetch checks, loading the cached ones");
+                let jobs = document.checks()?;
+                self.current = Some((document, jobs.clone()));
+                Ok(jobs)
+            }
+            result => result,
+        }
+    }
+
+    async fn fetch(&mut self) -> Result<Vec<Job>> {
+        let mut request = self.client.get(self.url.clone());
+        if let Some(etag) = self
+            .current
+            .as_ref()
+            .and_then(|(document, _)| document.etag.as_ref())
+        {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        let response = request.send().await?.error_for_status()?;
+
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let etag = header(ETAG);
+        let json = header(CONTENT_TYPE).is_some_and(|content_type| content_type.contains("json"));
+        let not_modified = response.status() == StatusCode::NOT_MODIFIED;
+        let body = response.text().await?;
+
+        // Documents are only parsed once they changed, whether the server supports
+        // ETags or not
+        if let Some((document, jobs)) = &mut self.current {
+            if not_modified || (document.body == body && document.json == json) {
+                if !not_modified {
+                    document.etag = etag;
+                }
+                return Ok(jobs.clone());
+            }
+        }
+
+        let document = Document { etag, json, body };
+        let jobs = document.checks()?;
+        if let Some(path) = &self.cache_path {
+            if let Err(e) = write_cache(path, &document).await {
+                tracing::warn!("Unable to cache checks in {}: {}", path.display(), e);
+            }
+        }
+        self.current = Some((document, jobs.clone()));
+        Ok(jobs)
+    }
+
+    async fn read_cache(&self) -> Option<Document> {
+        let path = self.cache_path.as_ref()?;
+        match tokio::fs::read(path).await {
+            Ok(content) => serde_json::from_slice(&content)
+                .map_err(|e| {
+                    tracing::warn!("Ignoring invalid cached checks {}: {}", path.display(), e)
+                })
+                .ok(),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => {
+                tracing::warn!("Unable to read cached checks {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+}
+
+/// Replace the cached document, a partially written cache is never read
+async fn write_cache(path: &Path, document: &Document) -> std::io::Result<()> {
+    let temporary = path.with_extension("tmp");
+    tokio::fs::write(&temporary, serde_json::to_vec(document)?).await?;
+    tokio::fs::rename(&temporary, path).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::sync::mpsc::UnboundedReceiver;
+
+    const YAML_CHECKS: &str = r#"
+checks:
+  - id: "01ARZ3NDEKTSV4RRWETS2EGZ5M"
+    type: "tcp"
+    pretty_name: "tcp"
+    endpoint: "127.0.0.1:8080"
+    secured: false
+    interval: 10
+"#;
+
+    /// Serve `responses` in order on a random local port, one per connection, sending
+    /// back the raw requests received
+    async fn serve(responses: Vec<String>) -> (String, UnboundedReceiver<String>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut buffer = [0u8; 4096];
+                let read = stream.read(&mut buffer).await.unwrap();
+                let _ = tx.send(String::from_utf8_lossy(&buffer[..read]).to_string());
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (format!("http://127.0.0.1:{}/checks", port), rx)
+    }
+
+    fn response(status_line: &str, headers: &str, body: &str) -> String {
+        format!(
+            "HTTP/1.1 {}\r\n{}content-length: {}\r\nconnection: close\r\n\r\n{}",
+            status_line,
+            headers,
+            body.len(),
+            body
+        )
+    }
+
+    fn http_checks(url: String, cache_path: Option<PathBuf>) -> HttpChecks {
+        HttpChecks::new(&HttpConfigCheckAdapter {
+            url,
+            poll_interval: 60,
+            headers: Default::default(),
+            cache_path,
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_http_checks_etag() {
+        let json_checks = r#"{"checks": []}"#;
+        let (url, mut requests) = serve(vec![
+            response("200 OK", "etag: \"v1\"\r\n", YAML_CHECKS),
+            response("304 Not Modified", "etag: \"v1\"\r\n", ""),
+            response(
+                "200 OK",
+                "etag: \"v2\"\r\ncontent-type: application/json\r\n",
+                json_checks,
+            ),
+        ])
+        .await;
+        let mut http_checks = http_checks(url, None);
+
+        let jobs = http_checks.pull().await.unwrap();
+        assert_eq!(jobs.len(), 1);
+        assert!(!requests.recv().await.unwrap().contains("if-none-match"));
+
+        assert_eq!(http_checks.pull().await.unwrap(), jobs);
+        assert!(requests
+            .recv()
+            .await
+            .unwrap()
+            .contains("if-none-match: \"v1\""));
+
+        assert!(http_checks.pull().await.unwrap().is_empty());
+        assert_eq!(http_checks.current.unwrap().0.etag.unwrap(), "\"v2\"");
+    }
+
+    #[tokio::test]
+    async fn test_http_checks_cache_fallback() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_path = dir.path().join("checks.json");
+        let (url, _requests) = serve(vec![response("200 OK", "", YAML_CHECKS)]).await;
+        let jobs = http_checks(url, Some(cache_path.clone()))
+            .pull()
+            .await
+            .unwrap();
+
+        // The source is down, the cached copy is loaded
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/checks", listener.local_addr().unwrap());
+        drop(listener);
+        let mut http_checks = http_checks(url, Some(cache_path));
+        assert_eq!(http_checks.pull().await.unwrap(), jobs);
+        // Once loaded, a source failure is reported so the running checks are kept
+        assert!(matches!(
+            http_checks.pull().await,
+            Err(Error::UnableToFetchChecks(_))
+        ));
+    }
+
+    // A document with an invalid check never replaces the last valid cached copy
+    #[tokio::test]
+    async fn test_http_checks_invalid_document_not_cached() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache_path = dir.path().join("checks.json");
+        let invalid_checks = r#"
+checks:
+  - type: "http"
+    pretty_name: "invalid header"
+    endpoint: "http://127.0.0.1:8080"
+    interval: 10
+    headers:
+      "invalid header": "value"
+"#;
+        let (url, _requests) = serve(vec![
+            response("200 OK", "", YAML_CHECKS),
+            response("200 OK", "", invalid_checks),
+        ])
+        .await;
+        let mut http_checks = http_checks(url, Some(cache_path));
+        let jobs = http_checks.pull().await.unwrap();
+        assert!(matches!(
+            http_checks.pull().await,
+            Err(Error::InvalidCheck { .. })
+        ));
+        assert_eq!(http_checks.current.as_ref().unwrap().1, jobs);
+
+        let cached = http_checks.read_cache().await.unwrap();
+        assert_eq!(cached.body, YAML_CHECKS);
+    }
+
+    // Remote documents can't read the agent environment and files
+    #[tokio::test]
+    async fn test_http_checks_references_not_interpolated() {
+        let dir = tempfile::tempdir().unwrap();
+        let secret = dir.path().join("secret");
+        std::fs::write(&secret, "agent-secret").unwrap();
+        let checks = |reference: &str| {
+            format!(
+                r#"
+checks:
+  - type: "http"
+    pretty_name: "exfiltration"
+    endpoint: "http://127.0.0.1:8080"
+    interval: 10
+    headers:
+      authorization: "Bearer {}{{file:{}}}"
+"#,
+                reference,
+                secret.display()
+            )
+        };
+        let (url, _requests) = serve(vec![response("200 OK", "", &checks("$"))]).await;
+        let jobs = http_checks(url, None).pull().await.unwrap();
+
+        // Same as a local check escaping the reference
+        let kept =
+            JobRegistry::read_configuration(Yaml::string(&checks("$$")), Interpolation::Enabled)
+                .unwrap();
+        assert_eq!(jobs, kept);
+    }
+}
@@ -0,0 +1,415 @@
+use crate::batch_sender::JobResult;
+use crate::jobs::{Execute, JobError};
+use async_trait::async_trait;
+use hickory_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use hickory_resolver::error::{ResolveError, ResolveErrorKind};
+use hickory_resolver::proto::rr::{RData, RecordType};
+use hickory_resolver::TokioAsyncResolver;
+use isok_data::broker_rpc::check_result::Details;
+use isok_data::broker_rpc::{CheckJobStatus, DnsRecord, JobDetailsDns};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
+
+/// Resolve a name and assert on the records of the answer
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct DnsJob {
+    /// Name to resolve, e.g. `example.com`
+    name: String,
+    #[serde(default)]
+    record_type: DnsRecordType,
+    /// Nameserver to query, either an IP address or an IP address and a port.
+    /// The system resolver is used when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    nameserver: Option<Nameserver>,
+    /// Values that must all be part of the answer, formatted like the `value` of
+    /// the reported records
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    expected: Vec<String>,
+    /// Minimum number of records in the answer
+    #[serde(default = "DnsJob::default_min_answers")]
+    min_answers: usize,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum DnsRecordType {
+    #[default]
+    A,
+    Aaaa,
+    Cname,
+    Mx,
+    Txt,
+    Srv,
+}
+
+impl From<DnsRecordType> for RecordType {
+    fn from(value: DnsRecordType) -> Self {
+        match value {
+            DnsRecordType::A => RecordType::A,
+            DnsRecordType::Aaaa => RecordType::AAAA,
+            DnsRecordType::Cname => RecordType::CNAME,
+            DnsRecordType::Mx => RecordType::MX,
+            DnsRecordType::Txt => RecordType::TXT,
+            DnsRecordType::Srv => RecordType::SRV,
+        }
+    }
+}
+
+/// Address of a nameserver, port 53 being used when not given
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy)]
+#[serde(try_from = "String", into = "String")]
+pub struct Nameserver(SocketAddr);
+
+impl TryFrom<String> for Nameserver {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if let Ok(addr) = value.parse::<SocketAddr>() {
+            return Ok(Nameserver(addr));
+        }
+        value
+            .parse::<IpAddr>()
+            .map(|ip| Nameserver(SocketAddr::new(ip, DnsJob::DNS_PORT)))
+            .map_err(|_| format!("Invalid nameserver {}", value))
+    }
+}
+
+impl From<Nameserver> for String {
+    fn from(value: Nameserver) -> Self {
+        if value.0.port() == DnsJob::DNS_PORT {
+            value.0.ip().to_string()
+        } else {
+            value.0.to_string()
+        }
+    }
+}
+
+impl DnsJob {
+    const DNS_PORT: u16 = 53;
+
+    pub fn new(name: String, record_type: DnsRecordType) -> Self {
+        DnsJob {
+            name,
+            record_type,
+            nameserver: None,
+            expected: vec![],
+            min_answers: Self::default_min_answers(),
+        }
+    }
+
+    pub fn with_nameserver(mut self, nameserver: SocketAddr) -> Self {
+        self.nameserver = Some(Nameserver(nameserver));
+        self
+    }
+
+    pub fn with_expected(mut self, expected: Vec<String>) -> Self {
+        self.expected = expected;
+        self
+    }
+
+    pub fn with_min_answers(mut self, min_answers: usize) -> Self {
+        self.min_answers = min_answers;
+        self
+    }
+
+    fn default_min_answers() -> usize {
+        1
+    }
+
+    fn resolver(&self) -> Result<TokioAsyncResolver, ResolveError> {
+        let Some(Nameserver(addr)) = self.nameserver else {
+            return TokioAsyncResolver::tokio_from_system_conf();
+        };
+        let config = ResolverConfig::from_parts(
+            None,
+            vec![],
+            NameServerConfigGroup::from_ips_clear(&[addr.ip()], addr.port(), true),
+        );
+        let mut options = ResolverOpts::default();
+        // Every execution must reach the nameserver
+        options.cache_size = 0;
+        Ok(TokioAsyncResolver::tokio(config, options))
+    }
+
+    /// Check the records of the answer against the assertions of the job
+    fn evaluate(&self, records: &[DnsRecord]) -> Result<(), String> {
+        if records.len() < self.min_answers {
+            return Err(format!(
+                "Expected at least {} records, got {}",
+                self.min_answers,
+                records.len()
+            ));
+        }
+        for expected in &self.expected {
+            if !records
+                .iter()
+                .any(|record| record.value.eq_ignore_ascii_case(expected))
+            {
+                return Err(format!(
+                    "Expected record {} isn't part of the answer",
+                    expected
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Value of a record, names being formatted without their trailing dot
+fn record_value(data: &RData) -> Option<String> {
+    let name = |name: &hickory_resolver::Name| name.to_utf8().trim_end_matches('.').to_string();
+    Some(match data {
+        RData::A(ip) => ip.to_string(),
+        RData::AAAA(ip) => ip.to_string(),
+        RData::CNAME(cname) => name(cname),
+        RData::MX(mx) => format!("{} {}", mx.preference(), name(mx.exchange())),
+        RData::TXT(txt) => txt
+            .iter()
+            .map(|data| String::from_utf8_lossy(data))
+            .collect::<String>(),
+        RData::SRV(srv) => format!(
+            "{} {} {} {}",
+            srv.priority(),
+            srv.weight(),
+            srv.port(),
+            name(srv.target())
+        ),
+        _ => return None,
+    })
+}
+
+#[async_trait]
+impl Execute for DnsJob {
+    async fn execute(&self, msg: &mut JobResult) -> Result<(), JobError> {
+        let resolver = match self.resolver() {
+            Ok(resolver) => resolver,
+            Err(e) => {
+                msg.set_status(CheckJobStatus::Unreachable);
+                msg.set_reason(format!("Unable to create resolver: {}", e));
+                return Ok(());
+            }
+        };
+
+        let record_type = RecordType::from(self.record_type);
+        let start_time = Instant::now();
+        let lookup = resolver.lookup(self.name.as_str(), record_type).await;
+        msg.set_latency(start_time.elapsed());
+
+        let records = match lookup {
+            Ok(lookup) => lookup
+                .record_iter()
+                .filter(|record| record.record_type() == record_type)
+                .filter_map(|record| {
+                    Some(DnsRecord {
+                        record_type: record_type.to_string(),
+                        value: record_value(record.data()?)?,
+                        ttl: record.ttl(),
+                    })
+                })
+                .collect(),
+            // An empty answer is a valid response, assertions decide whether it's expected
+            Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => vec![],
+            Err(e) => {
+                msg.set_status(CheckJobStatus::Unreachable);
+                msg.set_reason(format!("Unable to resolve {}: {}", self.name, e));
+                return Ok(());
+            }
+        };
+
+        match self.evaluate(&records) {
+            Ok(()) => msg.set_status(CheckJobStatus::Reachable),
+            Err(reason) => {
+                msg.set_status(CheckJobStatus::AssertionFailed);
+                msg.set_reason(reason);
+            }
+        }
+        msg.set_details(Details::DetailsDns(JobDetailsDns {
+            records,
+            nameserver: self.nameserver.map(|nameserver| nameserver.0.to_string()),
+        }));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jobs::JobInnerConfig;
+    use hickory_resolver::proto::op::{Message, MessageType};
+    use hickory_resolver::proto::rr::rdata::{A, MX, TXT};
+    use hickory_resolver::proto::rr::Record;
+    use hickory_resolver::Name;
+    use isok_data::JobId;
+    use pretty_assertions::assert_eq;
+    use std::str::FromStr;
+    use tokio::net::UdpSocket;
+
+    /// Answer every query on a local UDP socket with the records of its type
+    async fn serve_dns(records: Vec<RData>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+                let request = Message::from_vec(&buf[..len]).unwrap();
+                let mut response = Message::new();
+                response
+                    .set_id(request.id())
+                    .set_message_type(MessageType::Response)
+                    .set_recursion_desired(request.recursion_desired())
+                    .set_recursion_available(true);
+                for query in request.queries() {
+                    response.add_query(query.clone());
+                    for data in records
+                        .iter()
+                        .filter(|data| data.record_type() == query.query_type())
+                    {
+                        response.add_answer(Record::from_rdata(
+                            query.name().clone(),
+                            300,
+                            data.clone(),
+                        ));
+                    }
+                }
+                socket
+                    .send_to(&response.to_vec().unwrap(), peer)
+                    .await
+                    .unwrap();
+            }
+        });
+        addr
+    }
+
+    async fn execute(job: DnsJob) -> JobResult {
+        let mut job_result = JobResult::new(JobId::generate());
+        job.execute(&mut job_result)
+            .await
+            .expect("Expected execution to succeed");
+        job_result
+    }
+
+    fn records(job_result: &JobResult) -> Vec<DnsRecord> {
+        let Some(Details::DetailsDns(details)) = job_result.details.clone() else {
+            panic!("Expected DNS details, got {:?}", job_result);
+        };
+        details.records
+    }
+
+    fn test_records() -> Vec<RData> {
+        let name = |name: &str| Name::from_str(name).unwrap();
+        vec![
+            RData::A(A::new(192, 0, 2, 1)),
+            RData::A(A::new(192, 0, 2, 2)),
+            RData::MX(MX::new(10, name("mail.example.test."))),
+            RData::TXT(TXT::new(vec!["v=spf1 ".to_string(), "-all".to_string()])),
+        ]
+    }
+
+    #[test]
+    fn test_dns_job_serde() {
+        let config: JobInnerConfig = serde_yaml::from_str(
+            r#"
+            type: "dns"
+            name: "example.com"
+            record_type: "MX"
+            nameserver: "192.0.2.53"
+            expected: ["10 mail.example.com"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            JobInnerConfig::Dns(
+                DnsJob::new("example.com".to_string(), DnsRecordType::Mx)
+                    .with_nameserver("192.0.2.53:53".parse().unwrap())
+                    .with_expected(vec!["10 mail.example.com".to_string()])
+            )
+        );
+        assert_eq!(
+            serde_yaml::to_value(&config).unwrap()["nameserver"],
+            serde_yaml::Value::from("192.0.2.53")
+        );
+
+        for config in [
+            r#"{ type: "dns", name: "example.com", nameserver: "not an address" }"#,
+            r#"{ type: "dns", name: "example.com", record_type: "PTR" }"#,
+        ] {
+            assert!(
+                serde_yaml::from_str::<JobInnerConfig>(config).is_err(),
+                "Expected {} to be rejected",
+                config
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dns_job_a_records() {
+        let nameserver = serve_dns(test_records()).await;
+        let job = DnsJob::new("example.test".to_string(), DnsRecordType::A)
+            .with_nameserver(nameserver)
+            .with_expected(vec!["192.0.2.2".to_string()])
+            .with_min_answers(2);
+        let job_result = execute(job).await;
+
+        assert_eq!(job_result.status, CheckJobStatus::Reachable);
+        assert!(job_result.latency.is_some());
+        assert_eq!(
+            records(&job_result),
+            vec![
+                DnsRecord {
+                    record_type: "A".to_string(),
+                    value: "192.0.2.1".to_string(),
+                    ttl: 300,
+                },
+                DnsRecord {
+                    record_type: "A".to_string(),
+                    value: "192.0.2.2".to_string(),
+                    ttl: 300,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_dns_job_mx_and_txt_records() {
+        let nameserver = serve_dns(test_records()).await;
+        let job = DnsJob::new("example.test".to_string(), DnsRecordType::Mx)
+            .with_nameserver(nameserver)
+            .with_expected(vec!["10 mail.example.test".to_string()]);
+        let job_result = execute(job).await;
+        assert_eq!(job_result.status, CheckJobStatus::Reachable);
+
+        let job = DnsJob::new("example.test".to_string(), DnsRecordType::Txt)
+            .with_nameserver(nameserver)
+            .with_expected(vec!["v=spf1 -all".to_string()]);
+        let job_result = execute(job).await;
+        assert_eq!(job_result.status, CheckJobStatus::Reachable);
+        assert_eq!(records(&job_result).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_dns_job_assertions_failed() {
+        let nameserver = serve_dns(test_records()).await;
+        let job = DnsJob::new("example.test".to_string(), DnsRecordType::A)
+            .with_nameserver(nameserver)
+            .with_expected(vec!["192.0.2.3".to_string()]);
+        let job_result = execute(job).await;
+        assert_eq!(job_result.status, CheckJobStatus::AssertionFailed);
+        assert_eq!(
+            job_result.reason,
+            Some("Expected record 192.0.2.3 isn't part of the answer".to_string())
+        );
+
+        let job = DnsJob::new("example.test".to_string(), DnsRecordType::Aaaa)
+            .with_nameserver(nameserver);
+        let job_result = execute(job).await;
+        assert_eq!(job_result.status, CheckJobStatus::AssertionFailed);
+        assert_eq!(
+            job_result.reason,
+            Some("Expected at least 1 records, got 0".to_string())
+        );
+        assert_eq!(records(&job_result), vec![]);
+    }
+}
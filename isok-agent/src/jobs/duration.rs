@@ -0,0 +1,248 @@
+//! Durations of the check configuration, written as a number of seconds or as a
+//! human-readable string such as `500ms`, `30s`, `5m`, `1h` or `1m30s`. Durations are
+//! bounded by [MAX_DURATION].
+
+use serde::de::{Error, Unexpected, Visitor};
+use serde::{Deserializer, Serializer};
+use std::fmt::Formatter;
+use std::time::Duration;
+
+/// Shortest interval between two executions of a check, the scheduler looking for
+/// checks to run at this rate
+pub(crate) const MIN_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Longest duration accepted, so schedules computed from it never overflow
+pub(crate) const MAX_DURATION: Duration = Duration::from_secs(365 * 24 * 3600);
+
+/// Units by decreasing length, along with their number of nanoseconds
+const UNITS: [(&str, u64); 6] = [
+    ("h", 3_600_000_000_000),
+    ("m", 60_000_000_000),
+    ("s", 1_000_000_000),
+    ("ms", 1_000_000),
+    ("us", 1_000),
+    ("ns", 1),
+];
+
+/// `duration`, rejected above [MAX_DURATION]
+fn bounded(duration: Duration) -> Result<Duration, String> {
+    if duration > MAX_DURATION {
+        return Err(format!(
+            "Duration {} is too long, the maximum is {}",
+            format(duration),
+            format(MAX_DURATION)
+        ));
+    }
+    Ok(duration)
+}
+
+/// Parse a human-readable duration, made of numbers each followed by a unit
+pub(crate) fn parse(value: &str) -> Result<Duration, String> {
+    let invalid = || {
+        format!(
+            "Invalid duration {}, expected e.g. `500ms`, `30s`, `5m` or `1h`",
+            value
+        )
+    };
+    let mut rest = value.trim();
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+    // A number of seconds, e.g. interpolated from an environment variable
+    if let Ok(seconds) = rest.parse::<u64>() {
+        return bounded(Duration::from_secs(seconds));
+    }
+
+    let mut nanos: u64 = 0;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let amount: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+        rest = &rest[digits..];
+        let unit = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let (_, factor) = UNITS
+            .iter()
+            .find(|(name, _)| *name == &rest[..unit])
+            .ok_or_else(invalid)?;
+        nanos = amount
+            .checked_mul(*factor)
+            .and_then(|amount| nanos.checked_add(amount))
+            .ok_or_else(|| {
+                format!(
+                    "Duration {} is too long, the maximum is {}",
+                    value,
+                    format(MAX_DURATION)
+                )
+            })?;
+        rest = &rest[unit..];
+    }
+    bounded(Duration::from_nanos(nanos))
+}
+
+/// Format `duration` in its largest exact unit, so it's parsed back as is
+pub(crate) fn format(duration: Duration) -> String {
+    let nanos = duration.as_nanos();
+    let (unit, factor) = UNITS
+        .iter()
+        .map(|(unit, factor)| (unit, u128::from(*factor)))
+        .find(|(_, factor)| nanos.is_multiple_of(*factor) && nanos >= *factor)
+        .unwrap_or((&"ns", 1));
+    format!("{}{}", nanos / factor, unit)
+}
+
+struct DurationVisitor;
+
+impl Visitor<'_> for DurationVisitor {
+    type Value = Duration;
+
+    fn expecting(&self, formatter: &mut Formatter) -> std::fmt::Result {
+        formatter.write_str("a number of seconds or a duration such as `500ms` or `5m`")
+    }
+
+    fn visit_u64<E: Error>(self, value: u64) -> Result<Self::Value, E> {
+        bounded(Duration::from_secs(value)).map_err(E::custom)
+    }
+
+    fn visit_i64<E: Error>(self, value: i64) -> Result<Self::Value, E> {
+        let seconds =
+            u64::try_from(value).map_err(|_| E::invalid_value(Unexpected::Signed(value), &self))?;
+        self.visit_u64(seconds)
+    }
+
+    fn visit_str<E: Error>(self, value: &str) -> Result<Self::Value, E> {
+        parse(value).map_err(E::custom)
+    }
+}
+
+pub(crate) fn serialize<S: Serializer>(
+    duration: &Duration,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format(*duration))
+}
+
+pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Duration, D::Error> {
+    deserializer.deserialize_any(DurationVisitor)
+}
+
+/// Interval between executions, rejected below [MIN_INTERVAL]
+pub(crate) mod interval {
+    use super::*;
+
+    pub(crate) use super::serialize;
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Duration, D::Error> {
+        let interval = super::deserialize(deserializer)?;
+        if interval < MIN_INTERVAL {
+            return Err(D::Error::custom(format!(
+                "Interval {} is too short, the minimum is {}",
+                format(interval),
+                format(MIN_INTERVAL)
+            )));
+        }
+        Ok(interval)
+    }
+}
+
+/// Duration of an execution, rejected when zero
+pub(crate) mod timeout {
+    use super::*;
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Duration, D::Error> {
+        let timeout = super::deserialize(deserializer)?;
+        if timeout.is_zero() {
+            return Err(D::Error::custom("Duration must be greater than zero"));
+        }
+        Ok(timeout)
+    }
+}
+
+/// Optional duration of an execution, rejected when zero
+pub(crate) mod optional {
+    use super::*;
+    use serde::Deserialize;
+
+    pub(crate) fn serialize<S: Serializer>(
+        duration: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => super::serialize(duration, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        #[derive(Deserialize)]
+        struct Wrapper(#[serde(deserialize_with = "super::timeout::deserialize")] Duration);
+
+        Ok(Option::<Wrapper>::deserialize(deserializer)?.map(|Wrapper(duration)| duration))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_and_format() {
+        for (value, duration, formatted) in [
+            ("500ms", Duration::from_millis(500), "500ms"),
+            ("30s", Duration::from_secs(30), "30s"),
+            ("5m", Duration::from_secs(300), "5m"),
+            ("1h", Duration::from_secs(3600), "1h"),
+            ("1m30s", Duration::from_secs(90), "90s"),
+            ("120s", Duration::from_secs(120), "2m"),
+            ("1s500ms", Duration::from_millis(1500), "1500ms"),
+            ("10", Duration::from_secs(10), "10s"),
+            ("1500us", Duration::from_micros(1500), "1500us"),
+            (
+                "1s250ns",
+                Duration::from_nanos(1_000_000_250),
+                "1000000250ns",
+            ),
+            ("8760h", MAX_DURATION, "8760h"),
+        ] {
+            assert_eq!(parse(value), Ok(duration), "{}", value);
+            assert_eq!(format(duration), formatted);
+            assert_eq!(parse(&format(duration)), Ok(duration));
+        }
+
+        for value in ["", "s", "5 m", "-5s", "1.5s", "3d"] {
+            assert!(parse(value).is_err(), "Expected {} to be rejected", value);
+        }
+    }
+
+    #[test]
+    fn test_max_duration() {
+        for value in ["8761h", "8760h1ns", "999999999h", "31536001"] {
+            assert!(
+                parse(value).is_err_and(|e| e.ends_with("the maximum is 8760h")),
+                "Expected {} to be rejected",
+                value
+            );
+        }
+        assert!(deserialize(&mut serde_json::Deserializer::from_str("31536001")).is_err());
+        assert_eq!(
+            deserialize(&mut serde_json::Deserializer::from_str("31536000")).unwrap(),
+            MAX_DURATION
+        );
+        // Durations built in code are formatted without loss
+        assert_eq!(
+            format(Duration::MAX),
+            format!("{}ns", Duration::MAX.as_nanos())
+        );
+    }
+}
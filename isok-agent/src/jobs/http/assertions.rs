@@ -0,0 +1,329 @@
+use regex::Regex;
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json_path::JsonPath;
+use std::time::Duration;
+
+/// Declarative assertions evaluated against the response of an HTTP check.
+/// When none is configured, any status code from 200 to 399 is considered healthy.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Default)]
+pub struct HttpAssertions {
+    /// Accepted status codes, either a single code (`200`) or an inclusive range (`"200-299"`)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    status_codes: Vec<StatusCodeRange>,
+    /// Substring the response body must contain
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body_contains: Option<String>,
+    /// Regular expression the response body must match
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body_matches: Option<BodyRegex>,
+    /// JSON paths that must resolve to the expected values in the response body
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    json_path: Vec<JsonPathAssertion>,
+    /// Headers that must be present in the response, optionally with an expected value
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    headers: Vec<HeaderAssertion>,
+    /// Maximum accepted latency, in milliseconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_latency_ms: Option<u64>,
+}
+
+/// Inclusive range of accepted HTTP status codes
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+#[serde(try_from = "StatusCodeRangeRepr", into = "StatusCodeRangeRepr")]
+pub struct StatusCodeRange {
+    start: u16,
+    end: u16,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum StatusCodeRangeRepr {
+    Single(u16),
+    Range(String),
+}
+
+impl TryFrom<StatusCodeRangeRepr> for StatusCodeRange {
+    type Error = String;
+
+    fn try_from(value: StatusCodeRangeRepr) -> Result<Self, Self::Error> {
+        let (start, end) = match value {
+            StatusCodeRangeRepr::Single(code) => (code, code),
+            StatusCodeRangeRepr::Range(range) => {
+                let parse = |s: &str| {
+                    s.trim()
+                        .parse::<u16>()
+                        .map_err(|_| format!("Invalid status code range {}", range))
+                };
+                match range.split_once('-') {
+                    Some((start, end)) => (parse(start)?, parse(end)?),
+                    None => {
+                        let code = parse(&range)?;
+                        (code, code)
+                    }
+                }
+            }
+        };
+        if start > end || StatusCode::from_u16(start).is_err() || StatusCode::from_u16(end).is_err()
+        {
+            return Err(format!("Invalid status code range {}-{}", start, end));
+        }
+        Ok(StatusCodeRange { start, end })
+    }
+}
+
+impl From<StatusCodeRange> for StatusCodeRangeRepr {
+    fn from(value: StatusCodeRange) -> Self {
+        if value.start == value.end {
+            StatusCodeRangeRepr::Single(value.start)
+        } else {
+            StatusCodeRangeRepr::Range(format!("{}-{}", value.start, value.end))
+        }
+    }
+}
+
+impl StatusCodeRange {
+    fn contains(&self, status: StatusCode) -> bool {
+        (self.start..=self.end).contains(&status.as_u16())
+    }
+}
+
+/// Regular expression compiled when the configuration is loaded
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(try_from = "String", into = "String")]
+pub struct BodyRegex(Regex);
+
+impl TryFrom<String> for BodyRegex {
+    type Error = regex::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Ok(BodyRegex(Regex::new(&value)?))
+    }
+}
+
+impl From<BodyRegex> for String {
+    fn from(value: BodyRegex) -> Self {
+        value.0.as_str().to_string()
+    }
+}
+
+impl PartialEq for BodyRegex {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct JsonPathAssertion {
+    path: JsonPath,
+    equals: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct HeaderAssertion {
+    name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+}
+
+impl HttpAssertions {
+    const DEFAULT_STATUS_CODES: StatusCodeRange = StatusCodeRange {
+        start: 200,
+        end: 399,
+    };
+
+    /// Evaluate every assertion against the response, returning a human-readable
+    /// reason for the first one that isn't satisfied.
+    pub(crate) fn evaluate(
+        &self,
+        status: StatusCode,
+        headers: &HeaderMap,
+        body: &[u8],
+        latency: Duration,
+    ) -> Result<(), String> {
+        let status_accepted = if self.status_codes.is_empty() {
+            Self::DEFAULT_STATUS_CODES.contains(status)
+        } else {
+            self.status_codes.iter().any(|range| range.contains(status))
+        };
+        if !status_accepted {
+            return Err(format!("Unexpected status code {}", status.as_u16()));
+        }
+
+        if let Some(max_latency_ms) = self.max_latency_ms {
+            if latency > Duration::from_millis(max_latency_ms) {
+                return Err(format!(
+                    "Latency of {}ms exceeds the maximum of {}ms",
+                    latency.as_millis(),
+                    max_latency_ms
+                ));
+            }
+        }
+
+        for header in &self.headers {
+            match (headers.get(&header.name), &header.value) {
+                (None, _) => return Err(format!("Missing response header {}", header.name)),
+                (Some(actual), Some(expected)) if actual.as_bytes() != expected.as_bytes() => {
+                    return Err(format!(
+                        "Response header {} is {:?}, expected {:?}",
+                        header.name,
+                        String::from_utf8_lossy(actual.as_bytes()),
+                        expected
+                    ));
+                }
+                _ => {}
+            }
+        }
+
+        let text = String::from_utf8_lossy(body);
+        if let Some(expected) = &self.body_contains {
+            if !text.contains(expected.as_str()) {
+                return Err(format!("Response body doesn't contain {:?}", expected));
+            }
+        }
+        if let Some(regex) = &self.body_matches {
+            if !regex.0.is_match(&text) {
+                return Err(format!(
+                    "Response body doesn't match regex {:?}",
+                    regex.0.as_str()
+                ));
+            }
+        }
+
+        if !self.json_path.is_empty() {
+            let json = serde_json::from_slice::<serde_json::Value>(body)
+                .map_err(|e| format!("Response body isn't valid JSON: {}", e))?;
+            for assertion in &self.json_path {
+                match assertion.path.query(&json).exactly_one() {
+                    Ok(value) if value == &assertion.equals => {}
+                    Ok(value) => {
+                        return Err(format!(
+                            "JSON path {} is {}, expected {}",
+                            assertion.path, value, assertion.equals
+                        ));
+                    }
+                    Err(_) => {
+                        return Err(format!(
+                            "JSON path {} doesn't resolve to a single value",
+                            assertion.path
+                        ));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use reqwest::header::HeaderValue;
+
+    fn assertions(config: &str) -> HttpAssertions {
+        serde_yaml::from_str(config).expect("Expected valid assertions")
+    }
+
+    #[test]
+    fn test_default_status_codes() {
+        let assertions = HttpAssertions::default();
+        let headers = HeaderMap::new();
+        assert!(assertions
+            .evaluate(StatusCode::OK, &headers, b"", Duration::ZERO)
+            .is_ok());
+        assert_eq!(
+            assertions.evaluate(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                &headers,
+                b"",
+                Duration::ZERO
+            ),
+            Err("Unexpected status code 500".to_string())
+        );
+    }
+
+    #[test]
+    fn test_status_code_ranges() {
+        let assertions = assertions(
+            r#"
+            status_codes: [204, "400-404"]
+            "#,
+        );
+        let headers = HeaderMap::new();
+        for status in [StatusCode::NO_CONTENT, StatusCode::NOT_FOUND] {
+            assert!(assertions
+                .evaluate(status, &headers, b"", Duration::ZERO)
+                .is_ok());
+        }
+        assert!(assertions
+            .evaluate(StatusCode::OK, &headers, b"", Duration::ZERO)
+            .is_err());
+    }
+
+    #[test]
+    fn test_invalid_config_rejected() {
+        for config in [
+            r#"status_codes: ["299-200"]"#,
+            r#"status_codes: ["abc"]"#,
+            r#"body_matches: "(unclosed""#,
+            r#"json_path: [{ path: "not a path", equals: 1 }]"#,
+        ] {
+            assert!(
+                serde_yaml::from_str::<HttpAssertions>(config).is_err(),
+                "Expected {} to be rejected",
+                config
+            );
+        }
+    }
+
+    #[test]
+    fn test_body_and_headers() {
+        let assertions = assertions(
+            r#"
+            body_contains: "healthy"
+            body_matches: "^\\{.*\\}$"
+            json_path:
+              - path: "$.status"
+                equals: "healthy"
+              - path: "$.checks[0].ok"
+                equals: true
+            headers:
+              - name: "Content-Type"
+                value: "application/json"
+              - name: "x-request-id"
+            max_latency_ms: 100
+            "#,
+        );
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", HeaderValue::from_static("application/json"));
+        headers.insert("x-request-id", HeaderValue::from_static("abcd"));
+        let body = br#"{"status":"healthy","checks":[{"ok":true}]}"#;
+
+        assert_eq!(
+            assertions.evaluate(StatusCode::OK, &headers, body, Duration::from_millis(10)),
+            Ok(())
+        );
+        assert_eq!(
+            assertions.evaluate(StatusCode::OK, &headers, body, Duration::from_millis(150)),
+            Err("Latency of 150ms exceeds the maximum of 100ms".to_string())
+        );
+        assert_eq!(
+            assertions.evaluate(
+                StatusCode::OK,
+                &headers,
+                br#"{"status":"healthy","checks":[{"ok":false}]}"#,
+                Duration::ZERO
+            ),
+            Err("JSON path $.checks[0].ok is false, expected true".to_string())
+        );
+
+        headers.remove("x-request-id");
+        assert_eq!(
+            assertions.evaluate(StatusCode::OK, &headers, body, Duration::ZERO),
+            Err("Missing response header x-request-id".to_string())
+        );
+    }
+}
@@ -0,0 +1,621 @@
+use crate::batch_sender::JobResult;
+use crate::jobs::http::assertions::HttpAssertions;
+use crate::jobs::http::request::{HttpAuth, HttpBody, HttpMethod};
+use crate::jobs::http::timing::{TracingLayer, TracingResolver, TracingSessionStore};
+use crate::jobs::secret::Secret;
+use crate::jobs::{tls, Execute, JobError};
+use async_trait::async_trait;
+use isok_data::broker_rpc::check_result::Details;
+use isok_data::broker_rpc::{CheckJobStatus, JobDetailsHttp};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::redirect::Policy;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+use tokio_rustls::rustls::client::Resumption;
+
+pub mod assertions;
+pub mod request;
+pub mod timing;
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct HttpJob {
+    endpoint: String,
+    /// Headers of the request, values are kept out of logs as they often carry credentials
+    headers: HashMap<String, Secret>,
+    /// HTTP method of the request, `GET` by default
+    #[serde(default, skip_serializing_if = "HttpMethod::is_default")]
+    method: HttpMethod,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    body: Option<HttpBody>,
+    /// Query parameters appended to the endpoint
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    query: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    auth: Option<HttpAuth>,
+    /// Follow redirections, the redirection response is evaluated otherwise
+    #[serde(default = "HttpJob::default_follow_redirects")]
+    follow_redirects: bool,
+    /// Maximum number of redirections followed, the check is unreachable beyond it
+    #[serde(default = "HttpJob::default_max_redirects")]
+    max_redirects: usize,
+    /// Assertions the response must satisfy for the check to be healthy
+    #[serde(default, skip_serializing_if = "is_default")]
+    assertions: HttpAssertions,
+    /// Open new connections on every execution, so the connection and TLS setup
+    /// are part of the measured latency. Connections are reused otherwise.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    fresh_connections: bool,
+    #[serde(skip)]
+    prepared: Prepared,
+}
+
+/// Headers and client built once from the job configuration, and shared by
+/// every execution
+#[derive(Debug, Clone, Default)]
+struct Prepared(OnceLock<PreparedRequest>);
+
+#[derive(Debug, Clone)]
+struct PreparedRequest {
+    headers: HeaderMap,
+    client: reqwest::Client,
+}
+
+/// Derived from the job configuration, it doesn't take part in comparisons
+impl PartialEq for Prepared {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+fn is_default<T: Default + PartialEq>(value: &T) -> bool {
+    value == &T::default()
+}
+
+impl HttpJob {
+    pub fn new(endpoint: String) -> Self {
+        Self {
+            endpoint,
+            headers: HashMap::from([("Content-Type".to_string(), "application/json".into())]),
+            method: HttpMethod::default(),
+            body: None,
+            query: BTreeMap::new(),
+            auth: None,
+            follow_redirects: Self::default_follow_redirects(),
+            max_redirects: Self::default_max_redirects(),
+            assertions: HttpAssertions::default(),
+            fresh_connections: false,
+            prepared: Prepared::default(),
+        }
+    }
+
+    pub fn with_assertions(mut self, assertions: HttpAssertions) -> Self {
+        self.assertions = assertions;
+        self
+    }
+
+    pub fn with_request(
+        mut self,
+        method: HttpMethod,
+        body: Option<HttpBody>,
+        query: BTreeMap<String, String>,
+        auth: Option<HttpAuth>,
+    ) -> Self {
+        self.method = method;
+        self.body = body;
+        self.query = query;
+        self.auth = auth;
+        self
+    }
+
+    pub fn with_redirects(mut self, follow_redirects: bool, max_redirects: usize) -> Self {
+        self.follow_redirects = follow_redirects;
+        self.max_redirects = max_redirects;
+        self
+    }
+
+    pub fn with_fresh_connections(mut self, fresh_connections: bool) -> Self {
+        self.fresh_connections = fresh_connections;
+        self
+    }
+
+    fn headers(&self) -> Result<HeaderMap, JobError> {
+        let mut headers_map = HeaderMap::new();
+        for (key, value) in self.headers.iter() {
+            let header_name = HeaderName::from_str(key).map_err(|_| {
+                JobError::InvalidJobConfig(format!("Header name {} is invalid", key))
+            })?;
+            let mut header_value = HeaderValue::from_str(value.expose()).map_err(|_| {
+                JobError::InvalidJobConfig(format!("Header value {} is invalid", key))
+            })?;
+            header_value.set_sensitive(true);
+            headers_map.insert(header_name, header_value);
+        }
+        Ok(headers_map)
+    }
+
+    fn build_client(&self, headers: HeaderMap) -> Result<reqwest::Client, JobError> {
+        let max_redirects = self.max_redirects;
+        let redirect_policy = if self.follow_redirects {
+            Policy::custom(move |attempt| {
+                if attempt.previous().len() > max_redirects {
+                    return attempt.error("too many redirects");
+                }
+                timing::record_redirect(attempt.previous().len() as u32);
+                attempt.follow()
+            })
+        } else {
+            Policy::none()
+        };
+
+        let roots = tls::system_roots().map_err(JobError::InvalidJobConfig)?;
+        let mut tls_config = tls::client_config(roots).map_err(JobError::InvalidJobConfig)?;
+        tls_config.resumption = Resumption::store(Arc::new(TracingSessionStore::default()));
+
+        Ok(reqwest::Client::builder()
+            .default_headers(headers)
+            .redirect(redirect_policy)
+            .use_preconfigured_tls(tls_config)
+            .dns_resolver(Arc::new(TracingResolver))
+            .connector_layer(TracingLayer)
+            .build()?)
+    }
+
+    /// Headers and client of the job, built on first use
+    fn prepared(&self) -> Result<&PreparedRequest, JobError> {
+        if let Some(prepared) = self.prepared.0.get() {
+            return Ok(prepared);
+        }
+        let headers = self.headers()?;
+        let client = self.build_client(headers.clone())?;
+        Ok(self
+            .prepared
+            .0
+            .get_or_init(|| PreparedRequest { headers, client }))
+    }
+
+    fn default_follow_redirects() -> bool {
+        true
+    }
+
+    /// Same limit as the default reqwest redirect policy
+    fn default_max_redirects() -> usize {
+        10
+    }
+}
+
+#[async_trait]
+impl Execute for HttpJob {
+    fn prepare(&self) -> Result<(), JobError> {
+        self.prepared().map(|_| ())
+    }
+
+    async fn execute(&self, msg: &mut JobResult) -> Result<(), JobError> {
+        let prepared = self.prepared()?;
+        let client = if self.fresh_connections {
+            self.build_client(prepared.headers.clone())?
+        } else {
+            prepared.client.clone()
+        };
+
+        let mut request = client
+            .request(self.method.method(), &self.endpoint)
+            .query(&self.query);
+        if let Some(body) = &self.body {
+            request = body.apply(request);
+        }
+        if let Some(auth) = &self.auth {
+            request = auth.apply(request);
+        }
+
+        let start_time = Instant::now();
+        let (response, trace) = timing::traced(request.send()).await;
+        match response {
+            Ok(response) => {
+                let first_byte = Instant::now();
+                let latency = first_byte - start_time;
+                msg.set_latency(latency);
+
+                let status = response.status();
+                let headers = response.headers().clone();
+                let mut details = JobDetailsHttp {
+                    status_code: status.as_u16() as u32,
+                    remote_address: response.remote_addr().map(|addr| addr.to_string()),
+                    http_version: format!("{:?}", response.version()),
+                    response_size: 0,
+                    redirect_count: trace.redirect_count,
+                };
+
+                let body = response.bytes().await;
+                // Phases completed before a body read failure are still reported
+                msg.set_timings(trace.timings(start_time, first_byte, Instant::now()));
+                let body = match body {
+                    Ok(body) => body,
+                    Err(e) => {
+                        msg.set_status(CheckJobStatus::Unreachable);
+                        msg.set_reason(format!(
+                            "Unable to read response body: {}",
+                            error_chain(&e)
+                        ));
+                        msg.set_details(Details::DetailsHttp(details));
+                        return Ok(());
+                    }
+                };
+                details.response_size = body.len() as u64;
+                msg.set_details(Details::DetailsHttp(details));
+
+                match self.assertions.evaluate(status, &headers, &body, latency) {
+                    Ok(()) => msg.set_status(CheckJobStatus::Reachable),
+                    Err(reason) => {
+                        msg.set_status(CheckJobStatus::AssertionFailed);
+                        msg.set_reason(reason);
+                    }
+                }
+            }
+            Err(e) => {
+                msg.set_status(CheckJobStatus::Unreachable);
+                msg.set_reason(error_chain(&e));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Format an error along with its sources, reqwest errors only give context
+/// at the top level, the actual cause (DNS, TLS...) lies in the sources.
+fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut reason = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        reason.push_str(": ");
+        reason.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    reason
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use isok_data::JobId;
+    use pretty_assertions::assert_eq;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Serve a single canned HTTP response on a random local port
+    async fn serve_once(status_line: &'static str, body: &'static str) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 1024];
+            let _ = stream.read(&mut buffer).await.unwrap();
+            let response = format!(
+                "HTTP/1.1 {}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                status_line,
+                body.len(),
+                body
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+        format!("http://127.0.0.1:{}", port)
+    }
+
+    /// Serve a single empty response on a random local port, sending back the raw
+    /// request it received
+    async fn serve_capture() -> (String, tokio::sync::oneshot::Receiver<String>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 4096];
+            let read = stream.read(&mut buffer).await.unwrap();
+            let _ = tx.send(String::from_utf8_lossy(&buffer[..read]).to_string());
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\nconnection: close\r\n\r\n")
+                .await
+                .unwrap();
+        });
+        (format!("http://127.0.0.1:{}/graphql", port), rx)
+    }
+
+    /// Serve empty responses on a random local port, keeping connections alive, and
+    /// count the accepted connections
+    async fn serve_keep_alive() -> (String, Arc<AtomicUsize>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let counter = connections.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::Relaxed);
+                tokio::spawn(async move {
+                    let mut buffer = [0u8; 1024];
+                    while stream.read(&mut buffer).await.is_ok_and(|read| read > 0) {
+                        let response = b"HTTP/1.1 204 No Content\r\n\r\n";
+                        if stream.write_all(response).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        (format!("http://127.0.0.1:{}", port), connections)
+    }
+
+    /// Serve a single redirection to `location` on a random local port
+    async fn serve_redirect(location: String) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 1024];
+            let _ = stream.read(&mut buffer).await.unwrap();
+            let response = format!(
+                "HTTP/1.1 302 Found\r\nlocation: {}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                location
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+        });
+        format!("http://127.0.0.1:{}", port)
+    }
+
+    #[tokio::test]
+    async fn test_http_job_server_error_fails() {
+        let endpoint = serve_once("500 Internal Server Error", "{}").await;
+        let mut job_result = JobResult::new(JobId::generate());
+        HttpJob::new(endpoint)
+            .execute(&mut job_result)
+            .await
+            .expect("Expected execution to succeed");
+        assert_eq!(job_result.status, CheckJobStatus::AssertionFailed);
+        assert_eq!(
+            job_result.reason,
+            Some("Unexpected status code 500".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_http_job_body_assertion() {
+        let assertions = serde_yaml::from_str(
+            r#"
+            json_path:
+              - path: "$.status"
+                equals: "up"
+            "#,
+        )
+        .unwrap();
+
+        let endpoint = serve_once("200 OK", r#"{"status":"up"}"#).await;
+        let job = HttpJob::new(endpoint).with_assertions(assertions);
+        let mut job_result = JobResult::new(JobId::generate());
+        job.execute(&mut job_result).await.unwrap();
+        assert_eq!(job_result.status, CheckJobStatus::Reachable);
+        assert_eq!(job_result.reason, None);
+
+        let endpoint = serve_once("200 OK", r#"{"status":"down"}"#).await;
+        let job = HttpJob { endpoint, ..job };
+        let mut job_result = JobResult::new(JobId::generate());
+        job.execute(&mut job_result).await.unwrap();
+        assert_eq!(job_result.status, CheckJobStatus::AssertionFailed);
+    }
+
+    #[tokio::test]
+    async fn test_http_job_details() {
+        let target = serve_once("200 OK", r#"{"status":"up"}"#).await;
+        let target_address = target.trim_start_matches("http://").to_string();
+        let endpoint = serve_redirect(serve_redirect(target).await).await;
+
+        let mut job_result = JobResult::new(JobId::generate());
+        HttpJob::new(endpoint)
+            .execute(&mut job_result)
+            .await
+            .expect("Expected execution to succeed");
+        assert_eq!(job_result.status, CheckJobStatus::Reachable);
+        assert_eq!(
+            job_result.details,
+            Some(Details::DetailsHttp(JobDetailsHttp {
+                status_code: 200,
+                remote_address: Some(target_address),
+                http_version: "HTTP/1.1".to_string(),
+                response_size: 15,
+                redirect_count: 2,
+            }))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_http_job_unreachable_reason() {
+        let mut job_result = JobResult::new(JobId::generate());
+        HttpJob::new("http://127.0.0.1:65534".to_string())
+            .execute(&mut job_result)
+            .await
+            .expect("Expected execution to succeed");
+        assert_eq!(job_result.status, CheckJobStatus::Unreachable);
+        assert!(job_result.reason.is_some());
+    }
+
+    #[test]
+    fn test_http_job_request_serde() {
+        let job: HttpJob = serde_yaml::from_str(
+            r#"
+            endpoint: "https://example.com/graphql"
+            headers: {}
+            method: "POST"
+            body:
+              json:
+                query: "{ health }"
+            query:
+              verbose: "true"
+            auth:
+              type: "bearer"
+              token: "secret"
+            follow_redirects: false
+            "#,
+        )
+        .unwrap();
+        assert_eq!(job.method, HttpMethod::from(reqwest::Method::POST));
+        assert_eq!(
+            job.body,
+            Some(HttpBody::Json(serde_json::json!({ "query": "{ health }" })))
+        );
+        assert_eq!(job.query.get("verbose").map(String::as_str), Some("true"));
+        assert!(!job.follow_redirects);
+        assert_eq!(job.max_redirects, 10);
+
+        // Credentials are redacted once serialized
+        let serialized = serde_yaml::to_string(&job).unwrap();
+        assert!(!serialized.contains("secret"));
+        assert_eq!(
+            serde_yaml::from_str::<HttpJob>(&serialized).unwrap(),
+            HttpJob {
+                auth: Some(serde_yaml::from_str(r#"{ type: "bearer", token: "***" }"#).unwrap()),
+                ..job
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_http_job_request_options() {
+        let (endpoint, request) = serve_capture().await;
+        let job = HttpJob::new(endpoint).with_request(
+            reqwest::Method::POST.into(),
+            Some(HttpBody::Json(serde_json::json!({ "query": "{ health }" }))),
+            BTreeMap::from([("verbose".to_string(), "true".to_string())]),
+            Some(
+                serde_yaml::from_str(r#"{ type: "basic", username: "isok", password: "pass" }"#)
+                    .unwrap(),
+            ),
+        );
+        let mut job_result = JobResult::new(JobId::generate());
+        job.execute(&mut job_result).await.unwrap();
+        assert_eq!(job_result.status, CheckJobStatus::Reachable);
+
+        let request = request.await.unwrap().to_lowercase();
+        assert!(request.starts_with("post /graphql?verbose=true http/1.1"));
+        // base64 of "isok:pass"
+        assert!(request.contains("authorization: basic axnvazpwyxnz"));
+        assert!(request.ends_with(r#"{"query":"{ health }"}"#));
+    }
+
+    #[tokio::test]
+    async fn test_http_job_redirect_policy() {
+        let target = serve_once("200 OK", "{}").await;
+        let endpoint = serve_redirect(target).await;
+        let mut job_result = JobResult::new(JobId::generate());
+        HttpJob::new(endpoint)
+            .with_redirects(false, 10)
+            .execute(&mut job_result)
+            .await
+            .unwrap();
+        let Some(Details::DetailsHttp(details)) = job_result.details else {
+            panic!("Expected HTTP details");
+        };
+        assert_eq!(details.status_code, 302);
+        assert_eq!(details.redirect_count, 0);
+
+        let target = serve_once("200 OK", "{}").await;
+        let endpoint = serve_redirect(serve_redirect(target).await).await;
+        let mut job_result = JobResult::new(JobId::generate());
+        HttpJob::new(endpoint)
+            .with_redirects(true, 1)
+            .execute(&mut job_result)
+            .await
+            .unwrap();
+        assert_eq!(job_result.status, CheckJobStatus::Unreachable);
+        assert!(job_result
+            .reason
+            .is_some_and(|reason| reason.contains("too many redirects")));
+    }
+
+    #[tokio::test]
+    async fn test_http_job_connection_reuse() {
+        for (fresh_connections, expected_connections) in [(false, 1), (true, 3)] {
+            let (endpoint, connections) = serve_keep_alive().await;
+            let job = HttpJob::new(endpoint).with_fresh_connections(fresh_connections);
+            job.prepare().unwrap();
+            for _ in 0..3 {
+                let mut job_result = JobResult::new(JobId::generate());
+                job.execute(&mut job_result).await.unwrap();
+                assert_eq!(job_result.status, CheckJobStatus::Reachable);
+            }
+            assert_eq!(
+                connections.load(Ordering::Relaxed),
+                expected_connections,
+                "Unexpected connections count with fresh_connections: {}",
+                fresh_connections
+            );
+        }
+    }
+
+    #[test]
+    fn test_http_job_invalid_headers_rejected_on_prepare() {
+        let job: HttpJob = serde_yaml::from_str(
+            r#"
+            endpoint: "http://127.0.0.1:8080"
+            headers:
+              "x-token": "invalid\nvalue"
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(
+            job.prepare(),
+            Err(JobError::InvalidJobConfig(reason)) if reason == "Header value x-token is invalid"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_http_job_timings() {
+        let (endpoint, _) = serve_keep_alive().await;
+        let job = HttpJob::new(endpoint.replace("127.0.0.1", "localhost"));
+
+        let mut job_result = JobResult::new(JobId::generate());
+        job.execute(&mut job_result).await.unwrap();
+        let timings = job_result.timings.expect("Expected timings");
+        assert!(timings.dns_lookup.is_some());
+        assert!(timings.tcp_connect.is_some());
+        assert_eq!(timings.tls_handshake, None);
+        assert!(timings.total >= timings.time_to_first_byte + timings.download);
+
+        // The connection is reused, only the request phases are measured
+        let mut job_result = JobResult::new(JobId::generate());
+        job.execute(&mut job_result).await.unwrap();
+        let timings = job_result.timings.expect("Expected timings");
+        assert_eq!(timings.dns_lookup, None);
+        assert_eq!(timings.tcp_connect, None);
+    }
+
+    // The connection is dropped by the server in the middle of the body
+    #[tokio::test]
+    async fn test_http_job_truncated_body_timings() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 1024];
+            let _ = stream.read(&mut buffer).await.unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 100\r\n\r\n{\"status\":")
+                .await
+                .unwrap();
+        });
+        let job = HttpJob::new(format!("http://localhost:{}", port));
+
+        let mut job_result = JobResult::new(JobId::generate());
+        job.execute(&mut job_result).await.unwrap();
+        assert_eq!(job_result.status, CheckJobStatus::Unreachable);
+        assert!(job_result
+            .reason
+            .as_deref()
+            .is_some_and(|reason| reason.starts_with("Unable to read response body")));
+        let timings = job_result.timings.expect("Expected timings");
+        assert!(timings.dns_lookup.is_some());
+        assert!(timings.tcp_connect.is_some());
+        assert!(timings.total >= timings.time_to_first_byte + timings.download);
+    }
+}
@@ -0,0 +1,190 @@
+use crate::jobs::secret::Secret;
+use reqwest::header::HeaderValue;
+use reqwest::{Method, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// HTTP method of the request, validated when the configuration is loaded
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Default)]
+#[serde(try_from = "String", into = "String")]
+pub struct HttpMethod(Method);
+
+impl TryFrom<String> for HttpMethod {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Method::from_str(&value.to_uppercase())
+            .map(HttpMethod)
+            .map_err(|_| format!("Invalid HTTP method {}", value))
+    }
+}
+
+impl From<HttpMethod> for String {
+    fn from(value: HttpMethod) -> Self {
+        value.0.to_string()
+    }
+}
+
+impl HttpMethod {
+    pub(crate) fn is_default(&self) -> bool {
+        self.0 == Method::GET
+    }
+
+    pub(crate) fn method(&self) -> Method {
+        self.0.clone()
+    }
+}
+
+impl From<Method> for HttpMethod {
+    fn from(value: Method) -> Self {
+        HttpMethod(value)
+    }
+}
+
+/// Body sent along with the request, either `raw` or `json`
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+#[serde(try_from = "HttpBodyConfig", into = "HttpBodyConfig")]
+pub enum HttpBody {
+    /// Sent as is, the `Content-Type` header is up to the check headers
+    Raw(String),
+    /// Serialized to JSON, `Content-Type` defaults to `application/json`
+    Json(serde_json::Value),
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct HttpBodyConfig {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    raw: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    json: Option<serde_json::Value>,
+}
+
+impl TryFrom<HttpBodyConfig> for HttpBody {
+    type Error = String;
+
+    fn try_from(value: HttpBodyConfig) -> Result<Self, Self::Error> {
+        match (value.raw, value.json) {
+            (Some(raw), None) => Ok(HttpBody::Raw(raw)),
+            (None, Some(json)) => Ok(HttpBody::Json(json)),
+            _ => Err("Body must be either raw or json".to_string()),
+        }
+    }
+}
+
+impl From<HttpBody> for HttpBodyConfig {
+    fn from(value: HttpBody) -> Self {
+        match value {
+            HttpBody::Raw(raw) => HttpBodyConfig {
+                raw: Some(raw),
+                json: None,
+            },
+            HttpBody::Json(json) => HttpBodyConfig {
+                raw: None,
+                json: Some(json),
+            },
+        }
+    }
+}
+
+impl HttpBody {
+    pub(crate) fn apply(&self, request: RequestBuilder) -> RequestBuilder {
+        match self {
+            HttpBody::Raw(body) => request.body(body.clone()),
+            HttpBody::Json(body) => request.json(body),
+        }
+    }
+}
+
+/// Credentials sent in the `Authorization` header, validated when the
+/// configuration is loaded
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+#[serde(try_from = "HttpAuthConfig", into = "HttpAuthConfig")]
+pub struct HttpAuth(HttpAuthConfig);
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum HttpAuthConfig {
+    Basic {
+        username: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        password: Option<Secret>,
+    },
+    Bearer {
+        token: Secret,
+    },
+}
+
+impl TryFrom<HttpAuthConfig> for HttpAuth {
+    type Error = String;
+
+    fn try_from(value: HttpAuthConfig) -> Result<Self, Self::Error> {
+        match &value {
+            HttpAuthConfig::Basic { username, .. } if username.contains(':') => {
+                return Err("Basic auth username can't contain ':'".to_string());
+            }
+            HttpAuthConfig::Bearer { token }
+                if token.is_empty()
+                    || HeaderValue::from_str(&format!("Bearer {}", token.expose())).is_err() =>
+            {
+                return Err("Bearer token isn't a valid header value".to_string());
+            }
+            _ => {}
+        }
+        Ok(HttpAuth(value))
+    }
+}
+
+impl From<HttpAuth> for HttpAuthConfig {
+    fn from(value: HttpAuth) -> Self {
+        value.0
+    }
+}
+
+impl HttpAuth {
+    pub(crate) fn apply(&self, request: RequestBuilder) -> RequestBuilder {
+        match &self.0 {
+            HttpAuthConfig::Basic { username, password } => {
+                request.basic_auth(username, password.as_ref().map(Secret::expose))
+            }
+            HttpAuthConfig::Bearer { token } => request.bearer_auth(token.expose()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_method_normalized() {
+        let method: HttpMethod = serde_yaml::from_str("post").unwrap();
+        assert_eq!(method, HttpMethod::from(Method::POST));
+        assert_eq!(serde_yaml::to_string(&method).unwrap().trim(), "POST");
+    }
+
+    #[test]
+    fn test_invalid_request_config_rejected() {
+        assert!(serde_yaml::from_str::<HttpMethod>(r#""GE T""#).is_err());
+        for config in [
+            r#"{ type: "basic", username: "user:name" }"#,
+            r#"{ type: "bearer", token: "" }"#,
+            r#"{ type: "bearer", token: "abc\ndef" }"#,
+            r#"{ type: "digest", username: "user" }"#,
+        ] {
+            assert!(
+                serde_yaml::from_str::<HttpAuth>(config).is_err(),
+                "Expected {} to be rejected",
+                config
+            );
+        }
+        for config in [r#"{}"#, r#"{ raw: "a", json: {} }"#, r#"{ text: "a" }"#] {
+            assert!(
+                serde_yaml::from_str::<HttpBody>(config).is_err(),
+                "Expected {} to be rejected",
+                config
+            );
+        }
+    }
+}
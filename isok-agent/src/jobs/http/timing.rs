@@ -0,0 +1,303 @@
+//! Timing breakdown of HTTP requests.
+//!
+//! The HTTP client is shared by every execution of a check, its hooks (DNS resolver,
+//! connector layer, TLS session store and redirect policy) record their timestamps in
+//! a task-local [Trace]. They are all invoked from the task sending the request, so
+//! each execution only sees the events of its own request.
+
+use isok_data::broker_rpc::CheckJobMetrics;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use std::cell::Cell;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio_rustls::rustls::client::{
+    ClientSessionMemoryCache, ClientSessionStore, Tls12ClientSessionValue, Tls13ClientSessionValue,
+};
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::NamedGroup;
+use tower::{Layer, Service};
+
+tokio::task_local! {
+    static TRACE: Cell<Trace>;
+}
+
+/// Events of the request of the current execution
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Trace {
+    /// Redirections followed before the final response
+    pub(crate) redirect_count: u32,
+    /// Time at which the last redirection was followed
+    redirected_at: Option<Instant>,
+    connect_start: Option<Instant>,
+    dns_start: Option<Instant>,
+    dns_end: Option<Instant>,
+    tls_start: Option<Instant>,
+    connected: Option<Instant>,
+}
+
+/// Duration of each phase of an HTTP request. Connection phases are only known
+/// when the request established a new connection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HttpTimings {
+    pub dns_lookup: Option<Duration>,
+    pub tcp_connect: Option<Duration>,
+    pub tls_handshake: Option<Duration>,
+    /// From the request being sent, once connected, to the response headers
+    pub time_to_first_byte: Duration,
+    /// From the response headers to the end of the body
+    pub download: Duration,
+    pub total: Duration,
+}
+
+fn record(update: impl FnOnce(&mut Trace)) {
+    // Hooks invoked outside of a traced request, e.g. from a connection completed
+    // in background, have nothing to record
+    let _ = TRACE.try_with(|trace| {
+        let mut current = trace.get();
+        update(&mut current);
+        trace.set(current);
+    });
+}
+
+/// Run `request` while recording the events of its hooks
+pub(crate) async fn traced<F: Future>(request: F) -> (F::Output, Trace) {
+    TRACE
+        .scope(Cell::new(Trace::default()), async {
+            let output = request.await;
+            (output, TRACE.with(Cell::get))
+        })
+        .await
+}
+
+/// Called by the redirect policy before following a redirection
+pub(crate) fn record_redirect(redirect_count: u32) {
+    record(|trace| {
+        trace.redirect_count = redirect_count;
+        trace.redirected_at = Some(Instant::now());
+    });
+}
+
+impl Trace {
+    pub(crate) fn timings(&self, start: Instant, first_byte: Instant, end: Instant) -> HttpTimings {
+        let (dns_lookup, tcp_connect, tls_handshake) = match (self.connect_start, self.connected) {
+            (Some(connect_start), Some(connected)) => {
+                let tcp_start = self.dns_end.unwrap_or(connect_start);
+                let tcp_end = self.tls_start.unwrap_or(connected);
+                (
+                    self.dns_start
+                        .zip(self.dns_end)
+                        .map(|(dns_start, dns_end)| dns_end.saturating_duration_since(dns_start)),
+                    Some(tcp_end.saturating_duration_since(tcp_start)),
+                    self.tls_start
+                        .map(|tls_start| connected.saturating_duration_since(tls_start)),
+                )
+            }
+            _ => (None, None, None),
+        };
+        let request_sent = [Some(start), self.redirected_at, self.connected]
+            .into_iter()
+            .flatten()
+            .max()
+            .unwrap_or(start);
+
+        HttpTimings {
+            dns_lookup,
+            tcp_connect,
+            tls_handshake,
+            time_to_first_byte: first_byte.saturating_duration_since(request_sent),
+            download: end.saturating_duration_since(first_byte),
+            total: end.saturating_duration_since(start),
+        }
+    }
+}
+
+impl HttpTimings {
+    pub(crate) fn record(&self, metrics: &mut CheckJobMetrics) {
+        let as_micros = |duration: Duration| duration.as_micros() as u64;
+        metrics.dns_lookup_us = self.dns_lookup.map(as_micros);
+        metrics.tcp_connect_us = self.tcp_connect.map(as_micros);
+        metrics.tls_handshake_us = self.tls_handshake.map(as_micros);
+        metrics.time_to_first_byte_us = Some(as_micros(self.time_to_first_byte));
+        metrics.download_us = Some(as_micros(self.download));
+        metrics.total_us = Some(as_micros(self.total));
+    }
+}
+
+/// System resolver recording the duration of lookups
+#[derive(Debug, Default)]
+pub(crate) struct TracingResolver;
+
+impl Resolve for TracingResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            record(|trace| trace.dns_start = Some(Instant::now()));
+            // The port is set by the connector once resolved
+            let addrs: Vec<_> = tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            record(|trace| trace.dns_end = Some(Instant::now()));
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Layer of the connector recording when a new connection starts and is established,
+/// TLS included
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct TracingLayer;
+
+impl<S> Layer<S> for TracingLayer {
+    type Service = TracingConnector<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TracingConnector(inner)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct TracingConnector<S>(S);
+
+impl<S, R> Service<R> for TracingConnector<S>
+where
+    S: Service<R>,
+    S::Future: Send + 'static,
+    S::Response: Send + 'static,
+    S::Error: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: R) -> Self::Future {
+        let connecting = self.0.call(request);
+        Box::pin(async move {
+            // Events of a previous connection, e.g. before a redirection, are discarded
+            record(|trace| {
+                *trace = Trace {
+                    redirect_count: trace.redirect_count,
+                    redirected_at: trace.redirected_at,
+                    connect_start: Some(Instant::now()),
+                    ..Default::default()
+                }
+            });
+            let connection = connecting.await;
+            if connection.is_ok() {
+                record(|trace| trace.connected = Some(Instant::now()));
+            }
+            connection
+        })
+    }
+}
+
+/// TLS session cache recording the start of handshakes, the cache being looked up
+/// when the client hello is built
+#[derive(Debug)]
+pub(crate) struct TracingSessionStore(ClientSessionMemoryCache);
+
+impl Default for TracingSessionStore {
+    fn default() -> Self {
+        // Same size as the default rustls cache
+        TracingSessionStore(ClientSessionMemoryCache::new(256))
+    }
+}
+
+fn record_tls_start() {
+    record(|trace| {
+        trace.tls_start.get_or_insert_with(Instant::now);
+    });
+}
+
+impl ClientSessionStore for TracingSessionStore {
+    fn set_kx_hint(&self, server_name: ServerName<'static>, group: NamedGroup) {
+        self.0.set_kx_hint(server_name, group)
+    }
+
+    fn kx_hint(&self, server_name: &ServerName<'_>) -> Option<NamedGroup> {
+        record_tls_start();
+        self.0.kx_hint(server_name)
+    }
+
+    fn set_tls12_session(&self, server_name: ServerName<'static>, value: Tls12ClientSessionValue) {
+        self.0.set_tls12_session(server_name, value)
+    }
+
+    fn tls12_session(&self, server_name: &ServerName<'_>) -> Option<Tls12ClientSessionValue> {
+        record_tls_start();
+        self.0.tls12_session(server_name)
+    }
+
+    fn remove_tls12_session(&self, server_name: &ServerName<'static>) {
+        self.0.remove_tls12_session(server_name)
+    }
+
+    fn insert_tls13_ticket(
+        &self,
+        server_name: ServerName<'static>,
+        value: Tls13ClientSessionValue,
+    ) {
+        self.0.insert_tls13_ticket(server_name, value)
+    }
+
+    fn take_tls13_ticket(
+        &self,
+        server_name: &ServerName<'static>,
+    ) -> Option<Tls13ClientSessionValue> {
+        record_tls_start();
+        self.0.take_tls13_ticket(server_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_trace_timings() {
+        let start = Instant::now();
+        let at = |millis: u64| start + Duration::from_millis(millis);
+        let trace = Trace {
+            redirect_count: 0,
+            redirected_at: None,
+            connect_start: Some(at(0)),
+            dns_start: Some(at(0)),
+            dns_end: Some(at(5)),
+            tls_start: Some(at(15)),
+            connected: Some(at(40)),
+        };
+        assert_eq!(
+            trace.timings(start, at(100), at(130)),
+            HttpTimings {
+                dns_lookup: Some(Duration::from_millis(5)),
+                tcp_connect: Some(Duration::from_millis(10)),
+                tls_handshake: Some(Duration::from_millis(25)),
+                time_to_first_byte: Duration::from_millis(60),
+                download: Duration::from_millis(30),
+                total: Duration::from_millis(130),
+            }
+        );
+
+        // Reused connection, only the request phases are known
+        let trace = Trace::default();
+        let timings = trace.timings(start, at(20), at(25));
+        assert_eq!(timings.dns_lookup, None);
+        assert_eq!(timings.tcp_connect, None);
+        assert_eq!(timings.tls_handshake, None);
+        assert_eq!(timings.time_to_first_byte, Duration::from_millis(20));
+    }
+
+    #[tokio::test]
+    async fn test_session_store_records_tls_start() {
+        let store = TracingSessionStore::default();
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let (_, trace) = traced(async { store.kx_hint(&server_name) }).await;
+        assert!(trace.tls_start.is_some());
+
+        // Outside of a traced request, nothing is recorded
+        assert_eq!(store.kx_hint(&server_name), None);
+    }
+}
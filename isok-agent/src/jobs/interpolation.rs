@@ -0,0 +1,149 @@
+//! Interpolation of `${ENV_VAR}` and `${file:/path}` references in check fields,
+//! so secrets aren't written in the configuration. `$${` is kept as a literal `${`.
+
+use serde_json::Value;
+use std::path::PathBuf;
+
+#[derive(Debug, thiserror::Error)]
+pub enum InterpolationError {
+    #[error("Environment variable {0} isn't set")]
+    MissingVariable(String),
+    #[error("Unable to read secret file {path}: {source}")]
+    UnreadableFile {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Unterminated reference, `${{` must be closed by `}}`")]
+    Unterminated,
+}
+
+/// Value of an environment variable, looked up in the agent environment
+pub(crate) fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+/// Interpolate every string of `value`, environment variables being looked up with `env`
+pub(crate) fn interpolate_value(
+    value: &mut Value,
+    env: &dyn Fn(&str) -> Option<String>,
+) -> Result<(), InterpolationError> {
+    match value {
+        Value::String(string) if string.contains("${") => {
+            *string = interpolate(string, env)?;
+        }
+        Value::Array(values) => {
+            for value in values {
+                interpolate_value(value, env)?;
+            }
+        }
+        Value::Object(fields) => {
+            for value in fields.values_mut() {
+                interpolate_value(value, env)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn interpolate(
+    input: &str,
+    env: &dyn Fn(&str) -> Option<String>,
+) -> Result<String, InterpolationError> {
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            output.push_str(&rest[..start - 1]);
+            output.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+
+        output.push_str(&rest[..start]);
+        let reference = &rest[start + 2..];
+        let end = reference
+            .find('}')
+            .ok_or(InterpolationError::Unterminated)?;
+        output.push_str(&resolve(&reference[..end], env)?);
+        rest = &reference[end + 1..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+fn resolve(
+    reference: &str,
+    env: &dyn Fn(&str) -> Option<String>,
+) -> Result<String, InterpolationError> {
+    match reference.strip_prefix("file:") {
+        Some(path) => std::fs::read_to_string(path)
+            // Secret files usually end with a new line
+            .map(|content| content.trim_end_matches(['\n', '\r']).to_string())
+            .map_err(|source| InterpolationError::UnreadableFile {
+                path: PathBuf::from(path),
+                source,
+            }),
+        None => {
+            env(reference).ok_or_else(|| InterpolationError::MissingVariable(reference.to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    fn env(name: &str) -> Option<String> {
+        (name == "USER").then(|| "isok".to_string())
+    }
+
+    #[test]
+    fn test_interpolate() {
+        let dir = tempfile::tempdir().unwrap();
+        let secret = dir.path().join("token");
+        std::fs::write(&secret, "file-token\n").unwrap();
+
+        let mut value = json!({
+            "endpoint": "https://${USER}.example.com",
+            "headers": {
+                "authorization": format!("Bearer ${{file:{}}}", secret.display()),
+                "x-template": "$${kept}",
+            },
+            "interval": 10,
+            "expected": ["${USER}"],
+        });
+        interpolate_value(&mut value, &env).unwrap();
+        assert_eq!(
+            value,
+            json!({
+                "endpoint": "https://isok.example.com",
+                "headers": {
+                    "authorization": "Bearer file-token",
+                    "x-template": "${kept}",
+                },
+                "interval": 10,
+                "expected": ["isok"],
+            })
+        );
+    }
+
+    #[test]
+    fn test_missing_references() {
+        assert!(matches!(
+            interpolate("${MISSING}", &env),
+            Err(InterpolationError::MissingVariable(name)) if name == "MISSING"
+        ));
+        assert!(matches!(
+            interpolate("${file:/nonexistent/isok/secret}", &env),
+            Err(InterpolationError::UnreadableFile { .. })
+        ));
+        assert!(matches!(
+            interpolate("Bearer ${USER", &env),
+            Err(InterpolationError::Unterminated)
+        ));
+    }
+}
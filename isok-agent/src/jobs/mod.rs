@@ -1,43 +1,66 @@
 use crate::batch_sender::JobResult;
+use crate::jobs::dns::DnsJob;
 use crate::jobs::http::HttpJob;
+use crate::jobs::schedule::Schedule;
 use crate::jobs::tcp::TcpJob;
+use crate::jobs::tls_cert::TlsCertJob;
 use async_trait::async_trait;
 use enum_dispatch::enum_dispatch;
+use isok_data::broker_rpc::CheckJobStatus;
 use isok_data::JobId;
 use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::Instant;
 
+pub mod dns;
+pub(crate) mod duration;
 pub mod http;
+pub(crate) mod interpolation;
+pub mod schedule;
+pub mod secret;
 pub mod tcp;
+pub mod template;
+pub(crate) mod tls;
+pub mod tls_cert;
 
 #[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
 #[serde(tag = "type")]
 #[enum_dispatch(Execute)]
+#[allow(clippy::large_enum_variant)]
 pub enum JobInnerConfig {
     #[serde(rename = "tcp")]
     Tcp(TcpJob),
     #[serde(rename = "http")]
     Http(HttpJob),
+    #[serde(rename = "tls_cert")]
+    TlsCert(TlsCertJob),
+    #[serde(rename = "dns")]
+    Dns(DnsJob),
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
 pub struct Job {
     #[serde(default = "generate_id")]
     id: JobId,
-    #[serde(deserialize_with = "deserialize_duration")]
-    interval: Duration,
+    /// Every `interval` or on a cron `schedule`, within the `active_windows` if any
+    #[serde(flatten)]
+    schedule: Schedule,
+    /// Maximum duration of a single execution, the agent-wide default is
+    /// used when not set.
+    #[serde(
+        default,
+        with = "duration::optional",
+        skip_serializing_if = "Option::is_none"
+    )]
+    timeout: Option<Duration>,
     #[serde(flatten)]
     inner: JobInnerConfig,
     pretty_name: String,
-}
-
-fn deserialize_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
-where
-    D: serde::Deserializer<'de>,
-{
-    let s = u64::deserialize(deserializer)?;
-    Ok(Duration::from_secs(s))
+    /// File the job was loaded from, if any
+    #[serde(skip)]
+    source: Option<PathBuf>,
 }
 
 fn generate_id() -> JobId {
@@ -49,31 +72,77 @@ impl Job {
     pub fn new(interval: Duration, job_config: JobInnerConfig, pretty_name: String) -> Self {
         Self {
             id: JobId::generate(),
-            interval,
+            schedule: Schedule::every(interval),
+            timeout: None,
             inner: job_config,
             pretty_name,
+            source: None,
         }
     }
 
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub(crate) fn with_source(mut self, source: PathBuf) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// File the job was loaded from, when loaded from checks files
+    pub fn source(&self) -> Option<&Path> {
+        self.source.as_deref()
+    }
+
     pub fn id(&self) -> JobId {
         self.id.clone()
     }
 
-    pub(crate) fn interval(&self) -> Duration {
-        self.interval
+    pub(crate) fn schedule(&self) -> &Schedule {
+        &self.schedule
     }
 
     pub(crate) fn pretty_name(&self) -> String {
         self.pretty_name.clone()
     }
 
+    pub(crate) fn prepare(&self) -> Result<(), JobError> {
+        self.inner.prepare()
+    }
+
+    /// Timeout of the job, or `default` when the job doesn't define its own
+    pub(crate) fn timeout_or(&self, default: Duration) -> Duration {
+        self.timeout.unwrap_or(default)
+    }
+
+    /// Execute the job and send its result to `tx`. If the execution takes
+    /// longer than `timeout`, it is cancelled and reported as [CheckJobStatus::Timeout].
     #[tracing::instrument(skip_all, fields(self.id, self.pretty_name))]
-    pub(crate) async fn execute(&self, tx: UnboundedSender<JobResult>) -> Result<(), JobError> {
+    pub(crate) async fn execute(
+        &self,
+        tx: UnboundedSender<JobResult>,
+        timeout: Duration,
+    ) -> Result<(), JobError> {
         let mut job_result = JobResult::new(self.id());
-        match &self.inner {
-            JobInnerConfig::Tcp(job) => job.execute(&mut job_result).await,
-            JobInnerConfig::Http(job) => job.execute(&mut job_result).await,
-        }?;
+        let start_time = Instant::now();
+        let execution = async {
+            match &self.inner {
+                JobInnerConfig::Tcp(job) => job.execute(&mut job_result).await,
+                JobInnerConfig::Http(job) => job.execute(&mut job_result).await,
+                JobInnerConfig::TlsCert(job) => job.execute(&mut job_result).await,
+                JobInnerConfig::Dns(job) => job.execute(&mut job_result).await,
+            }
+        };
+
+        match tokio::time::timeout(timeout, execution).await {
+            Ok(result) => result?,
+            Err(_) => {
+                tracing::warn!(?timeout, "Job execution timed out");
+                job_result.set_status(CheckJobStatus::Timeout);
+                job_result.set_latency(start_time.elapsed());
+            }
+        }
 
         if let Err(e) = tx.send(job_result) {
             tracing::error!("Job failed to properly send its result to channel {}", e);
@@ -93,25 +162,65 @@ pub enum JobError {
 #[async_trait]
 #[enum_dispatch]
 pub trait Execute {
+    /// Build what doesn't change between executions, called once when the job is
+    /// registered so configuration errors are reported when it's loaded
+    fn prepare(&self) -> Result<(), JobError> {
+        Ok(())
+    }
+
     async fn execute(&self, job_result: &mut JobResult) -> Result<(), JobError>;
 }
 
+/// Host and port of a `host:port` endpoint, brackets around IPv6 addresses being removed
+pub(crate) fn host_and_port(endpoint: &str) -> Option<(&str, u16)> {
+    let (host, port) = endpoint.rsplit_once(':')?;
+    let host = match host.strip_prefix('[') {
+        Some(host) => host.strip_suffix(']')?,
+        None => host,
+    };
+    if host.is_empty() || host.contains(['[', ']']) {
+        return None;
+    }
+    Some((host, port.parse().ok()?))
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::jobs::{Job, JobInnerConfig};
+    use crate::jobs::{host_and_port, Job, JobInnerConfig};
     use std::time::Duration;
 
     use crate::jobs::http::HttpJob;
+    use crate::jobs::schedule::Schedule;
+    use isok_data::broker_rpc::CheckJobStatus;
     use isok_data::JobId;
     use serde::{Deserialize, Serialize};
 
+    #[test]
+    fn test_host_and_port() {
+        assert_eq!(host_and_port("example.com:443"), Some(("example.com", 443)));
+        assert_eq!(host_and_port("[::1]:443"), Some(("::1", 443)));
+        assert_eq!(host_and_port("::1:443"), Some(("::1", 443)));
+        for invalid in [
+            ":443",
+            "[]:443",
+            "[::1:443",
+            "::1]:443",
+            "example.com",
+            "example.com:https",
+        ] {
+            assert_eq!(host_and_port(invalid), None, "{}", invalid);
+        }
+    }
+
     #[test]
     fn test_see_output_of_job() {
         let job = Job {
             id: JobId::generate(),
-            interval: Duration::from_secs(10),
+            schedule: Schedule::every(Duration::from_secs(10)),
+            timeout: None,
             inner: JobInnerConfig::Http(HttpJob::new("https://google.com".to_string())),
             pretty_name: "google".to_string(),
+            source: None,
         };
         let str = serde_yaml::to_string(&job).unwrap();
         println!("{}", str);
@@ -154,4 +263,85 @@ mod tests {
         let root: DummyRootJob = serde_yaml::from_str(config).unwrap();
         assert_eq!(&root.jobs[0].id.to_string(), "01ARZ3NDEKTSV4RRWETS2EGZ5M");
     }
+
+    #[test]
+    fn test_job_timeout_serde() {
+        let config = r#"
+        jobs:
+            - type: "tcp"
+              pretty_name: "tcp with timeout"
+              endpoint: "127.0.0.1:9123"
+              secured: false
+              interval: 5
+              timeout: 2
+            "#;
+        let root: DummyRootJob = serde_yaml::from_str(config).unwrap();
+        assert_eq!(root.jobs[0].timeout, Some(Duration::from_secs(2)));
+        assert_eq!(
+            root.jobs[0].timeout_or(Duration::from_secs(10)),
+            Duration::from_secs(2)
+        );
+    }
+
+    #[test]
+    fn test_job_duration_serde() {
+        let config = r#"
+        jobs:
+            - type: "tcp"
+              id: "01ARZ3NDEKTSV4RRWETS2EGZ5M"
+              pretty_name: "tcp every 500ms"
+              endpoint: "127.0.0.1:9123"
+              secured: false
+              interval: "500ms"
+              timeout: "1m30s"
+            "#;
+        let root: DummyRootJob = serde_yaml::from_str(config).unwrap();
+        assert_eq!(
+            root.jobs[0].schedule,
+            Schedule::every(Duration::from_millis(500))
+        );
+        assert_eq!(root.jobs[0].timeout, Some(Duration::from_secs(90)));
+
+        // Serialized jobs are read back as is
+        let serialized = serde_yaml::to_string(&root).unwrap();
+        assert!(serialized.contains("interval: 500ms"));
+        let deserialized: DummyRootJob = serde_yaml::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.jobs, root.jobs);
+
+        for (interval, timeout) in [("0", "5s"), ("10ms", "5s"), ("10s", "0s")] {
+            let config = format!(
+                r#"{{ jobs: [{{ type: "tcp", pretty_name: "tcp", endpoint: "127.0.0.1:9123", secured: false, interval: "{}", timeout: "{}" }}] }}"#,
+                interval, timeout
+            );
+            assert!(
+                serde_yaml::from_str::<DummyRootJob>(&config).is_err(),
+                "Expected interval {} and timeout {} to be rejected",
+                interval,
+                timeout
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_job_execution_timeout() {
+        // Accept connections but never answer, the HTTP request hangs
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+        let job = Job::new(
+            Duration::from_secs(10),
+            JobInnerConfig::Http(HttpJob::new(format!("http://127.0.0.1:{}", port))),
+            "hanging".to_string(),
+        );
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        job.execute(tx, Duration::from_millis(200)).await.unwrap();
+
+        let result = rx.recv().await.unwrap();
+        assert_eq!(result.status, CheckJobStatus::Timeout);
+        assert!(result.latency.unwrap() >= Duration::from_millis(200));
+    }
 }
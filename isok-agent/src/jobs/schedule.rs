@@ -0,0 +1,413 @@
+//! When checks run: every `interval` or on a cron `schedule`, evaluated in the check
+//! `timezone`. Checks with `active_windows` only run within them, they're paused
+//! outside and resume as soon as a window opens.
+
+use crate::jobs::duration;
+use chrono::{DateTime, Datelike, Days, NaiveDateTime, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use croner::Cron;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Cron expression, with 5 fields or 6 when starting with seconds
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct CronExpression(Cron);
+
+impl TryFrom<String> for CronExpression {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Cron::new(&value)
+            .with_seconds_optional()
+            .parse()
+            .map(CronExpression)
+            .map_err(|e| format!("Invalid cron expression {}: {}", value, e))
+    }
+}
+
+impl From<CronExpression> for String {
+    fn from(value: CronExpression) -> Self {
+        value.0.as_str().to_string()
+    }
+}
+
+impl PartialEq for CronExpression {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl CronExpression {
+    fn next(&self, after: DateTime<Tz>, inclusive: bool) -> Option<DateTime<Tz>> {
+        self.0.find_next_occurrence(&after, inclusive).ok()
+    }
+}
+
+/// Time range within which a check runs, on the given days or every day
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "ActiveWindowConfig", into = "ActiveWindowConfig")]
+pub struct ActiveWindow {
+    days: Vec<Weekday>,
+    start: NaiveTime,
+    /// End of the window, excluded. The window spans midnight when it's before `start`.
+    end: NaiveTime,
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct ActiveWindowConfig {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    days: Vec<Weekday>,
+    start: String,
+    end: String,
+}
+
+fn parse_time(value: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(value, "%H:%M")
+        .or_else(|_| NaiveTime::parse_from_str(value, "%H:%M:%S"))
+        .map_err(|_| format!("Invalid time {}, expected `HH:MM` or `HH:MM:SS`", value))
+}
+
+impl TryFrom<ActiveWindowConfig> for ActiveWindow {
+    type Error = String;
+
+    fn try_from(value: ActiveWindowConfig) -> Result<Self, Self::Error> {
+        let start = parse_time(&value.start)?;
+        let end = parse_time(&value.end)?;
+        if start == end {
+            return Err("Active window start and end must differ".to_string());
+        }
+        Ok(ActiveWindow {
+            days: value.days,
+            start,
+            end,
+        })
+    }
+}
+
+impl From<ActiveWindow> for ActiveWindowConfig {
+    fn from(value: ActiveWindow) -> Self {
+        let format = |time: NaiveTime| time.format("%H:%M:%S").to_string();
+        ActiveWindowConfig {
+            days: value.days,
+            start: format(value.start),
+            end: format(value.end),
+        }
+    }
+}
+
+impl ActiveWindow {
+    fn runs_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    /// Whether the window contains `local`, days being the ones the window starts on
+    fn contains(&self, local: NaiveDateTime) -> bool {
+        let (time, day) = (local.time(), local.weekday());
+        if self.start < self.end {
+            self.runs_on(day) && self.start <= time && time < self.end
+        } else {
+            (self.start <= time && self.runs_on(day))
+                || (time < self.end && self.runs_on(day.pred()))
+        }
+    }
+
+    /// First opening of the window after `after`
+    fn next_opening(&self, after: DateTime<Tz>) -> Option<DateTime<Tz>> {
+        (0..=7)
+            .filter_map(|offset| after.date_naive().checked_add_days(Days::new(offset)))
+            .filter(|date| self.runs_on(date.weekday()))
+            // Openings skipped by a daylight saving time change are ignored
+            .filter_map(|date| {
+                after
+                    .timezone()
+                    .from_local_datetime(&date.and_time(self.start))
+                    .earliest()
+            })
+            .find(|opening| *opening > after)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[allow(clippy::large_enum_variant)]
+enum Trigger {
+    Interval(Duration),
+    Cron(CronExpression),
+}
+
+/// Schedule of a check, either `interval` or `schedule` being set
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "ScheduleConfig", into = "ScheduleConfig")]
+pub struct Schedule {
+    trigger: Trigger,
+    timezone: Tz,
+    active_windows: Vec<ActiveWindow>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Interval(#[serde(with = "duration::interval")] Duration);
+
+#[derive(Deserialize, Serialize)]
+struct ScheduleConfig {
+    /// Interval between two executions, e.g. `500ms`, `30s`, `5m` or a number of seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    interval: Option<Interval>,
+    /// Cron expression the check runs on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    schedule: Option<CronExpression>,
+    /// Timezone the cron expression and the active windows are evaluated in
+    #[serde(
+        default = "ScheduleConfig::default_timezone",
+        skip_serializing_if = "ScheduleConfig::is_utc"
+    )]
+    timezone: Tz,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    active_windows: Vec<ActiveWindow>,
+}
+
+impl ScheduleConfig {
+    fn default_timezone() -> Tz {
+        Tz::UTC
+    }
+
+    fn is_utc(timezone: &Tz) -> bool {
+        *timezone == Tz::UTC
+    }
+}
+
+impl TryFrom<ScheduleConfig> for Schedule {
+    type Error = String;
+
+    fn try_from(value: ScheduleConfig) -> Result<Self, Self::Error> {
+        let trigger = match (value.interval, value.schedule) {
+            (Some(Interval(interval)), None) => Trigger::Interval(interval),
+            (None, Some(schedule)) => Trigger::Cron(schedule),
+            (Some(_), Some(_)) => {
+                return Err("Either `interval` or `schedule` must be set, not both".to_string())
+            }
+            (None, None) => return Err("Either `interval` or `schedule` must be set".to_string()),
+        };
+        Ok(Schedule {
+            trigger,
+            timezone: value.timezone,
+            active_windows: value.active_windows,
+        })
+    }
+}
+
+impl From<Schedule> for ScheduleConfig {
+    fn from(value: Schedule) -> Self {
+        let (interval, schedule) = match value.trigger {
+            Trigger::Interval(interval) => (Some(Interval(interval)), None),
+            Trigger::Cron(schedule) => (None, Some(schedule)),
+        };
+        ScheduleConfig {
+            interval,
+            schedule,
+            timezone: value.timezone,
+            active_windows: value.active_windows,
+        }
+    }
+}
+
+impl Schedule {
+    /// Give up looking for a run within the active windows after this many attempts,
+    /// e.g. for a cron expression never matching them
+    const MAX_ATTEMPTS: usize = 1024;
+
+    /// Run every `interval`, at any time
+    pub fn every(interval: Duration) -> Self {
+        Schedule {
+            trigger: Trigger::Interval(interval),
+            timezone: Tz::UTC,
+            active_windows: vec![],
+        }
+    }
+
+    /// Whether the check may run at `at`
+    pub(crate) fn is_active(&self, at: DateTime<Utc>) -> bool {
+        let local = at.with_timezone(&self.timezone).naive_local();
+        self.active_windows.is_empty()
+            || self
+                .active_windows
+                .iter()
+                .any(|window| window.contains(local))
+    }
+
+    /// First run of a newly scheduled check, right away for interval checks
+    pub(crate) fn first_run(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let now = now.with_timezone(&self.timezone);
+        let candidate = match &self.trigger {
+            Trigger::Interval(_) => Some(now),
+            Trigger::Cron(schedule) => schedule.next(now, false),
+        };
+        self.within_windows(candidate?)
+    }
+
+    /// Run following the one at `now`
+    pub(crate) fn next_run(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let now = now.with_timezone(&self.timezone);
+        let candidate = match &self.trigger {
+            Trigger::Interval(interval) => {
+                now.checked_add_signed(chrono::Duration::from_std(*interval).ok()?)
+            }
+            Trigger::Cron(schedule) => schedule.next(now, false),
+        };
+        self.within_windows(candidate?)
+    }
+
+    /// Postpone `candidate` until it's within an active window
+    fn within_windows(&self, mut candidate: DateTime<Tz>) -> Option<DateTime<Utc>> {
+        for _ in 0..Self::MAX_ATTEMPTS {
+            if self.is_active(candidate.to_utc()) {
+                return Some(candidate.to_utc());
+            }
+            let opening = self
+                .active_windows
+                .iter()
+                .filter_map(|window| window.next_opening(candidate))
+                .min()?;
+            candidate = match &self.trigger {
+                Trigger::Interval(_) => opening,
+                Trigger::Cron(schedule) => schedule.next(opening, true)?,
+            };
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn schedule(config: &str) -> Result<Schedule, serde_yaml::Error> {
+        serde_yaml::from_str(config)
+    }
+
+    fn utc(datetime: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(datetime).unwrap().to_utc()
+    }
+
+    #[test]
+    fn test_interval_schedule() {
+        let schedule = schedule("interval: 30s").unwrap();
+        let now = utc("2025-01-06T10:00:00Z");
+        assert_eq!(schedule.first_run(now), Some(now));
+        assert_eq!(schedule.next_run(now), Some(utc("2025-01-06T10:00:30Z")));
+    }
+
+    #[test]
+    fn test_cron_schedule_in_timezone() {
+        let every_five_minutes = schedule(r#"{ schedule: "*/5 * * * *" }"#).unwrap();
+        assert_eq!(
+            every_five_minutes.first_run(utc("2025-01-06T10:02:30Z")),
+            Some(utc("2025-01-06T10:05:00Z"))
+        );
+        assert_eq!(
+            every_five_minutes.next_run(utc("2025-01-06T10:05:00Z")),
+            Some(utc("2025-01-06T10:10:00Z"))
+        );
+
+        // 9:00 in Paris, UTC+1 in winter and UTC+2 in summer
+        let morning = schedule(r#"{ schedule: "0 9 * * *", timezone: "Europe/Paris" }"#).unwrap();
+        assert_eq!(
+            morning.next_run(utc("2025-01-06T10:00:00Z")),
+            Some(utc("2025-01-07T08:00:00Z"))
+        );
+        assert_eq!(
+            morning.next_run(utc("2025-07-07T10:00:00Z")),
+            Some(utc("2025-07-08T07:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn test_active_windows() {
+        let business_hours = schedule(
+            r#"
+            interval: 10m
+            timezone: "Europe/Paris"
+            active_windows:
+              - days: ["mon", "tue", "wed", "thu", "fri"]
+                start: "09:00"
+                end: "18:00"
+            "#,
+        )
+        .unwrap();
+        // Monday 10:00 in Paris
+        assert!(business_hours.is_active(utc("2025-01-06T09:00:00Z")));
+        assert_eq!(
+            business_hours.next_run(utc("2025-01-06T09:00:00Z")),
+            Some(utc("2025-01-06T09:10:00Z"))
+        );
+        // Friday 17:55, resumes on Monday 9:00
+        assert_eq!(
+            business_hours.next_run(utc("2025-01-10T16:55:00Z")),
+            Some(utc("2025-01-13T08:00:00Z"))
+        );
+        // Saturday, paused until Monday
+        assert!(!business_hours.is_active(utc("2025-01-11T12:00:00Z")));
+        assert_eq!(
+            business_hours.first_run(utc("2025-01-11T12:00:00Z")),
+            Some(utc("2025-01-13T08:00:00Z"))
+        );
+
+        // Windows spanning midnight belong to the day they start on
+        let nightly = schedule(
+            r#"
+            schedule: "0 * * * *"
+            active_windows:
+              - days: ["fri"]
+                start: "22:00"
+                end: "02:00"
+            "#,
+        )
+        .unwrap();
+        assert!(nightly.is_active(utc("2025-01-11T01:30:00Z")));
+        assert!(!nightly.is_active(utc("2025-01-12T01:30:00Z")));
+        assert_eq!(
+            nightly.next_run(utc("2025-01-11T01:00:00Z")),
+            Some(utc("2025-01-17T22:00:00Z"))
+        );
+
+        // Never matching its window
+        let never = schedule(
+            r#"
+            schedule: "0 12 * * *"
+            active_windows: [{ start: "13:00", end: "14:00" }]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(never.next_run(utc("2025-01-06T10:00:00Z")), None);
+    }
+
+    #[test]
+    fn test_schedule_serde() {
+        let config = r#"
+            schedule: "0 */5 9-17 * * MON-FRI"
+            timezone: "America/New_York"
+            active_windows:
+              - start: "09:00"
+                end: "17:30"
+            "#;
+        let parsed = schedule(config).unwrap();
+        let serialized = serde_yaml::to_string(&parsed).unwrap();
+        assert_eq!(schedule(&serialized).unwrap(), parsed);
+
+        for config in [
+            "{}",
+            r#"{ interval: 10s, schedule: "* * * * *" }"#,
+            r#"{ schedule: "* * *" }"#,
+            r#"{ interval: 10s, timezone: "Mars/Olympus_Mons" }"#,
+            r#"{ interval: 10s, active_windows: [{ start: "9am", end: "18:00" }] }"#,
+            r#"{ interval: 10s, active_windows: [{ start: "09:00", end: "09:00" }] }"#,
+        ] {
+            assert!(
+                schedule(config).is_err(),
+                "Expected {} to be rejected",
+                config
+            );
+        }
+    }
+}
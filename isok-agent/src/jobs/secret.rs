@@ -0,0 +1,58 @@
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt::{Debug, Formatter};
+
+/// String kept out of logs, e.g. credentials. It's redacted from its debug output
+/// and when serialized, only [Secret::expose] gives access to the value.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    const REDACTED: &'static str = "***";
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Secret(value)
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self {
+        Secret(value.to_string())
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", Self::REDACTED)
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(Self::REDACTED)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_secret_redacted() {
+        let secret: Secret = serde_yaml::from_str("\"Bearer my-token\"").unwrap();
+        assert_eq!(secret.expose(), "Bearer my-token");
+        assert_eq!(format!("{:?}", secret), "\"***\"");
+        assert_eq!(serde_yaml::to_string(&secret).unwrap().trim(), "'***'");
+    }
+}
@@ -1,17 +1,65 @@
 use crate::batch_sender::JobResult;
-use crate::jobs::{Execute, JobError};
+use crate::jobs::{host_and_port, tls, Execute, JobError};
 use async_trait::async_trait;
-use isok_data::broker_rpc::CheckJobStatus;
+use isok_data::broker_rpc::check_result::Details;
+use isok_data::broker_rpc::{CheckJobStatus, JobDetailsTcp};
 use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
-use std::str::FromStr;
-use std::time::Instant;
-use tokio::net::TcpStream;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::{lookup_host, TcpStream};
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::TlsConnector;
 
 #[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
 pub struct TcpJob {
+    /// Address to connect to, either `ip:port` or `hostname:port`
     endpoint: String,
+    /// Perform a TLS handshake once connected, the check fails when the handshake
+    /// or the certificate verification fails
     secured: bool,
+    /// Server name sent in the TLS handshake and verified against the certificate,
+    /// the endpoint host by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sni: Option<String>,
+    /// PEM bundle of the authorities trusted to sign the server certificate,
+    /// the system roots are used when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ca_cert: Option<PathBuf>,
+    /// Address family tried first when the endpoint host resolves to several addresses
+    #[serde(default, skip_serializing_if = "IpPreference::is_any")]
+    ip_preference: IpPreference,
+    /// Try every resolved address in turn until a connection succeeds, instead of
+    /// only the first one
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    try_all_addresses: bool,
+    #[serde(skip)]
+    roots: tls::Roots,
+}
+
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum IpPreference {
+    /// Keep the order given by the resolver
+    #[default]
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+impl IpPreference {
+    fn is_any(&self) -> bool {
+        *self == IpPreference::Any
+    }
+
+    /// Sort key of an address, preferred addresses first
+    fn rank(&self, addr: &SocketAddr) -> u8 {
+        match (self, addr) {
+            (IpPreference::Ipv4, SocketAddr::V6(_)) | (IpPreference::Ipv6, SocketAddr::V4(_)) => 1,
+            _ => 0,
+        }
+    }
 }
 
 impl TcpJob {
@@ -19,29 +67,162 @@ impl TcpJob {
         TcpJob {
             endpoint,
             secured: false,
+            sni: None,
+            ca_cert: None,
+            ip_preference: IpPreference::default(),
+            try_all_addresses: false,
+            roots: tls::Roots::default(),
         }
     }
+
+    /// Perform a TLS handshake, optionally overriding the server name and the
+    /// trusted authorities
+    pub fn with_tls(mut self, sni: Option<String>, ca_cert: Option<PathBuf>) -> Self {
+        self.secured = true;
+        self.sni = sni;
+        self.ca_cert = ca_cert;
+        self
+    }
+
+    pub fn with_ip_preference(mut self, ip_preference: IpPreference) -> Self {
+        self.ip_preference = ip_preference;
+        self
+    }
+
+    pub fn with_try_all_addresses(mut self, try_all_addresses: bool) -> Self {
+        self.try_all_addresses = try_all_addresses;
+        self
+    }
+
+    /// Addresses of the endpoint, sorted by preference
+    async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, String> {
+        let mut addrs: Vec<SocketAddr> = match host.parse::<IpAddr>() {
+            Ok(ip) => vec![SocketAddr::new(ip, port)],
+            Err(_) => lookup_host((host, port))
+                .await
+                .map_err(|e| format!("Unable to resolve {}: {}", host, e))?
+                .collect(),
+        };
+        if addrs.is_empty() {
+            return Err(format!("Unable to resolve {}: no address found", host));
+        }
+        addrs.sort_by_key(|addr| self.ip_preference.rank(addr));
+        Ok(addrs)
+    }
+
+    /// Connect to the first reachable address, only the first one being tried
+    /// unless `try_all_addresses` is set
+    async fn connect(&self, addrs: &[SocketAddr]) -> Result<(SocketAddr, TcpStream), String> {
+        let candidates = if self.try_all_addresses {
+            addrs
+        } else {
+            &addrs[..1]
+        };
+        let mut errors = Vec::new();
+        for addr in candidates {
+            match TcpStream::connect(addr).await {
+                Ok(stream) => return Ok((*addr, stream)),
+                Err(e) => errors.push(format!("Unable to connect to {}: {}", addr, e)),
+            }
+        }
+        Err(errors.join(", "))
+    }
+
+    /// Complete a TLS handshake over the connected stream, returning the negotiated
+    /// TLS version
+    async fn handshake(
+        &self,
+        host: &str,
+        addr: SocketAddr,
+        stream: TcpStream,
+    ) -> Result<String, String> {
+        let server_name = self.sni.as_deref().unwrap_or(host);
+        let server_name = ServerName::try_from(server_name.to_string())
+            .map_err(|_| format!("Invalid server name {}", server_name))?;
+        let roots = self.roots.get_or_load(self.ca_cert.as_deref())?;
+        let config = tls::client_config(roots)?;
+
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(server_name, stream)
+            .await
+            .map_err(|e| format!("TLS handshake with {} failed: {}", addr, e))?;
+        let version = stream
+            .get_ref()
+            .1
+            .protocol_version()
+            .map(|version| format!("{:?}", version))
+            .unwrap_or_default();
+        Ok(version)
+    }
+}
+
+fn as_millis(duration: Duration) -> u64 {
+    duration.as_millis() as u64
 }
 
 #[async_trait]
 impl Execute for TcpJob {
+    fn prepare(&self) -> Result<(), JobError> {
+        if self.secured {
+            self.roots
+                .get_or_load(self.ca_cert.as_deref())
+                .map_err(JobError::InvalidJobConfig)?;
+        }
+        Ok(())
+    }
+
     async fn execute(&self, msg: &mut JobResult) -> Result<(), JobError> {
-        let addr = SocketAddr::from_str(&self.endpoint);
-        if let Ok(addr) = addr {
-            let start_time = Instant::now();
-            match TcpStream::connect(addr).await {
-                Ok(_) => {
-                    let latency = start_time.elapsed();
-                    msg.set_status(CheckJobStatus::Reachable);
-                    msg.set_latency(latency);
-                }
-                Err(_) => {
+        let Some((host, port)) = host_and_port(&self.endpoint) else {
+            msg.set_status(CheckJobStatus::Unreachable);
+            msg.set_reason(format!("Invalid endpoint {}", self.endpoint));
+            return Ok(());
+        };
+
+        let start_time = Instant::now();
+        let addrs = match self.resolve(host, port).await {
+            Ok(addrs) => addrs,
+            Err(reason) => {
+                msg.set_status(CheckJobStatus::Unreachable);
+                msg.set_reason(reason);
+                return Ok(());
+            }
+        };
+        let mut details = JobDetailsTcp {
+            resolved_addresses: addrs.iter().map(|addr| addr.to_string()).collect(),
+            ..Default::default()
+        };
+
+        let connect_start = Instant::now();
+        let (addr, stream) = match self.connect(&addrs).await {
+            Ok(connection) => connection,
+            Err(reason) => {
+                msg.set_status(CheckJobStatus::Unreachable);
+                msg.set_reason(reason);
+                msg.set_details(Details::DetailTcp(details));
+                return Ok(());
+            }
+        };
+        details.peer_address = Some(addr.to_string());
+        details.connect_latency = Some(as_millis(connect_start.elapsed()));
+
+        if self.secured {
+            let handshake_start = Instant::now();
+            let handshake = self.handshake(host, addr, stream).await;
+            details.tls_handshake_latency = Some(as_millis(handshake_start.elapsed()));
+            match handshake {
+                Ok(version) => details.tls_version = Some(version),
+                Err(reason) => {
                     msg.set_status(CheckJobStatus::Unreachable);
+                    msg.set_reason(reason);
+                    msg.set_details(Details::DetailTcp(details));
+                    return Ok(());
                 }
             }
-        } else {
-            msg.set_status(CheckJobStatus::Unreachable);
         }
+
+        msg.set_status(CheckJobStatus::Reachable);
+        msg.set_latency(start_time.elapsed());
+        msg.set_details(Details::DetailTcp(details));
         Ok(())
     }
 }
@@ -49,21 +230,37 @@ impl Execute for TcpJob {
 #[cfg(test)]
 mod tests {
     use crate::batch_sender::JobResult;
-    use crate::jobs::tcp::TcpJob;
+    use crate::jobs::tcp::{IpPreference, TcpJob};
+    use crate::jobs::tls::testing::serve_tls;
     use crate::jobs::Execute;
+    use crate::jobs::JobError;
+    use crate::jobs::JobInnerConfig;
+    use isok_data::broker_rpc::check_result::Details;
     use isok_data::broker_rpc::CheckJobStatus;
     use isok_data::JobId;
+    use std::net::SocketAddr;
+    use std::time::{Duration, SystemTime};
 
     #[tokio::test]
     async fn test_tcp_job() {
         let tcp = TcpJob {
             endpoint: "toto".to_string(),
             secured: false,
+            sni: None,
+            ca_cert: None,
+            ip_preference: IpPreference::Any,
+            try_all_addresses: false,
+            roots: Default::default(),
         };
 
         let tcp2 = TcpJob {
             endpoint: "tata".to_string(),
             secured: false,
+            sni: None,
+            ca_cert: None,
+            ip_preference: IpPreference::Any,
+            try_all_addresses: false,
+            roots: Default::default(),
         };
 
         let jobs = vec![tcp, tcp2];
@@ -76,6 +273,11 @@ mod tests {
         let tcp = TcpJob {
             endpoint: "toto".to_string(),
             secured: false,
+            sni: None,
+            ca_cert: None,
+            ip_preference: IpPreference::Any,
+            try_all_addresses: false,
+            roots: Default::default(),
         };
         let mut job_result = JobResult::new(JobId::generate());
         tcp.execute(&mut job_result)
@@ -99,12 +301,23 @@ mod tests {
         let tcp = TcpJob {
             endpoint: "127.0.0.1".to_string() + ":" + &port.to_string(),
             secured: false,
+            sni: None,
+            ca_cert: None,
+            ip_preference: IpPreference::Any,
+            try_all_addresses: false,
+            roots: Default::default(),
         };
         let mut job_result = JobResult::new(JobId::generate());
         tcp.execute(&mut job_result)
             .await
             .expect("Expected execution to succeed");
         assert_eq!(job_result.status, CheckJobStatus::Reachable);
+        let Some(Details::DetailTcp(details)) = job_result.details else {
+            panic!("Expected TCP details");
+        };
+        assert_eq!(details.peer_address, Some(format!("127.0.0.1:{}", port)));
+        assert!(details.connect_latency.is_some());
+        assert_eq!(details.tls_handshake_latency, None);
     }
 
     #[tokio::test]
@@ -112,6 +325,11 @@ mod tests {
         let tcp = TcpJob {
             endpoint: "127.0.0.1:65534".to_string(),
             secured: false,
+            sni: None,
+            ca_cert: None,
+            ip_preference: IpPreference::Any,
+            try_all_addresses: false,
+            roots: Default::default(),
         };
         let mut job_result = JobResult::new(JobId::generate());
         tcp.execute(&mut job_result)
@@ -119,4 +337,159 @@ mod tests {
             .expect("Expected execution to succeed");
         assert_eq!(job_result.status, CheckJobStatus::Unreachable);
     }
+
+    #[tokio::test]
+    async fn test_tcp_job_tls_handshake() {
+        let dir = tempfile::tempdir().unwrap();
+        let (port, ca_path) =
+            serve_tls(dir.path(), SystemTime::now() + Duration::from_secs(86400)).await;
+        let tcp = TcpJob::new(format!("127.0.0.1:{}", port))
+            .with_tls(Some("localhost".to_string()), Some(ca_path.clone()));
+        tcp.prepare()
+            .expect("Expected the CA certificate to be loaded");
+        // The CA certificate is only read when the job is prepared
+        std::fs::remove_file(ca_path).unwrap();
+        let mut job_result = JobResult::new(JobId::generate());
+        tcp.execute(&mut job_result)
+            .await
+            .expect("Expected execution to succeed");
+        assert_eq!(job_result.status, CheckJobStatus::Reachable);
+        let Some(Details::DetailTcp(details)) = job_result.details else {
+            panic!("Expected TCP details");
+        };
+        assert!(details.connect_latency.is_some());
+        assert!(details.tls_handshake_latency.is_some());
+        assert_eq!(details.tls_version, Some("TLSv1_3".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_job_tls_verification_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let (port, ca_path) =
+            serve_tls(dir.path(), SystemTime::now() + Duration::from_secs(86400)).await;
+        for tcp in [
+            // Certificate isn't valid for the server name
+            TcpJob::new(format!("127.0.0.1:{}", port))
+                .with_tls(Some("example.com".to_string()), Some(ca_path)),
+            // Certificate isn't signed by a trusted authority
+            TcpJob::new(format!("127.0.0.1:{}", port))
+                .with_tls(Some("localhost".to_string()), None),
+        ] {
+            let mut job_result = JobResult::new(JobId::generate());
+            tcp.execute(&mut job_result)
+                .await
+                .expect("Expected execution to succeed");
+            assert_eq!(job_result.status, CheckJobStatus::Unreachable);
+            assert!(job_result
+                .reason
+                .as_deref()
+                .is_some_and(|reason| reason.starts_with("TLS handshake with")));
+        }
+    }
+
+    #[test]
+    fn test_tcp_job_unreadable_ca_cert() {
+        let tcp = TcpJob::new("127.0.0.1:443".to_string()).with_tls(
+            Some("localhost".to_string()),
+            Some("/nonexistent/isok/ca.pem".into()),
+        );
+        assert!(matches!(
+            tcp.prepare(),
+            Err(JobError::InvalidJobConfig(reason)) if reason.starts_with("Unable to read CA certificate")
+        ));
+    }
+
+    #[test]
+    fn test_tcp_job_serde() {
+        let config: JobInnerConfig = serde_yaml::from_str(
+            r#"
+            type: "tcp"
+            endpoint: "example.com:443"
+            secured: false
+            ip_preference: "ipv6"
+            try_all_addresses: true
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            JobInnerConfig::Tcp(
+                TcpJob::new("example.com:443".to_string())
+                    .with_ip_preference(IpPreference::Ipv6)
+                    .with_try_all_addresses(true)
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tcp_job_hostname_endpoint() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Unable to bind to port");
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move { listener.accept().await });
+        let tcp = TcpJob::new(format!("localhost:{}", port)).with_ip_preference(IpPreference::Ipv4);
+        let mut job_result = JobResult::new(JobId::generate());
+        tcp.execute(&mut job_result)
+            .await
+            .expect("Expected execution to succeed");
+        assert_eq!(job_result.status, CheckJobStatus::Reachable);
+        let Some(Details::DetailTcp(details)) = job_result.details else {
+            panic!("Expected TCP details");
+        };
+        assert_eq!(details.peer_address, Some(format!("127.0.0.1:{}", port)));
+        assert_eq!(details.resolved_addresses[0], format!("127.0.0.1:{}", port));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_job_resolution_failure() {
+        let tcp = TcpJob::new("does-not-exist.invalid:80".to_string());
+        let mut job_result = JobResult::new(JobId::generate());
+        tcp.execute(&mut job_result)
+            .await
+            .expect("Expected execution to succeed");
+        assert_eq!(job_result.status, CheckJobStatus::Unreachable);
+        assert!(job_result
+            .reason
+            .as_deref()
+            .is_some_and(|reason| reason.starts_with("Unable to resolve does-not-exist.invalid")));
+        assert_eq!(job_result.details, None);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_job_address_order() {
+        let tcp = TcpJob::new("[::1]:80".to_string()).with_ip_preference(IpPreference::Ipv4);
+        let mut addrs: [SocketAddr; 2] =
+            ["[::1]:80".parse().unwrap(), "127.0.0.1:80".parse().unwrap()];
+        addrs.sort_by_key(|addr| tcp.ip_preference.rank(addr));
+        assert_eq!(addrs[0], "127.0.0.1:80".parse().unwrap());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Unable to bind to port");
+        let online = listener.local_addr().unwrap();
+        tokio::spawn(async move { listener.accept().await });
+        let addrs = ["127.0.0.1:65534".parse().unwrap(), online];
+
+        assert!(tcp.connect(&addrs).await.is_err());
+        let tcp = tcp.with_try_all_addresses(true);
+        let (addr, _) = tcp.connect(&addrs).await.expect("Expected a connection");
+        assert_eq!(addr, online);
+    }
+
+    #[tokio::test]
+    async fn test_tcp_job_tls_handshake_with_hostname() {
+        let dir = tempfile::tempdir().unwrap();
+        let (port, ca_path) =
+            serve_tls(dir.path(), SystemTime::now() + Duration::from_secs(86400)).await;
+        // The endpoint host is used as server name
+        let tcp = TcpJob::new(format!("localhost:{}", port))
+            .with_ip_preference(IpPreference::Ipv4)
+            .with_tls(None, Some(ca_path));
+        let mut job_result = JobResult::new(JobId::generate());
+        tcp.execute(&mut job_result)
+            .await
+            .expect("Expected execution to succeed");
+        assert_eq!(job_result.status, CheckJobStatus::Reachable);
+    }
 }
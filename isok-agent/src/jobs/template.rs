@@ -0,0 +1,459 @@
+//! Check defaults and templates, merged into the checks before they're parsed.
+//!
+//! Fields are merged from the `defaults`, then from each template a check `extends`
+//! by order, then from the check itself. Maps such as `headers` are merged key by
+//! key, any other field being overridden. A field set to `null` unsets the value
+//! it overrides. Setting `schedule` overrides an inherited `interval`, and the
+//! other way around. References to environment variables and secret files are then
+//! interpolated for local sources, see [interpolation](crate::jobs::interpolation).
+//!
+//! Checks without an `id` get one derived from their name, so they're matched
+//! across reloads as long as their name doesn't change.
+
+use crate::jobs::interpolation::{env_var, interpolate_value, InterpolationError};
+use crate::jobs::Job;
+use isok_data::JobId;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use ulid::Ulid;
+
+/// Field of a check naming the templates it extends
+const EXTENDS: &str = "extends";
+/// Field of a check holding its ID
+const ID: &str = "id";
+/// Field of a check holding its name
+const PRETTY_NAME: &str = "pretty_name";
+/// Fields a check can only set one of, setting either of them overrides the other
+const TRIGGERS: [&str; 2] = ["interval", "schedule"];
+
+#[derive(Debug, thiserror::Error)]
+pub enum TemplateError {
+    #[error("Check {check} extends unknown template {template}")]
+    UnknownTemplate { check: String, template: String },
+    #[error("Template {0} can't extend another template")]
+    NestedTemplate(String),
+    #[error("`extends` of check {0} must be a template name or a list of them")]
+    InvalidExtends(String),
+    #[error("Unable to interpolate check {check}: {source}")]
+    Interpolation {
+        check: String,
+        #[source]
+        source: InterpolationError,
+    },
+    #[error("Checks {first} and {second} have the same ID {id}, checks without an `id` must have a unique name")]
+    DuplicateId {
+        id: String,
+        first: String,
+        second: String,
+    },
+    #[error("Check {check} is invalid: {source}")]
+    InvalidCheck {
+        check: String,
+        #[source]
+        source: serde_json::Error,
+    },
+}
+
+/// Whether references to environment variables and secret files are interpolated.
+/// Checks of remote sources must not read the agent environment and files, their
+/// references are kept as is.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interpolation {
+    Enabled,
+    Disabled,
+}
+
+/// Checks along with the defaults and templates they're merged with
+#[derive(Debug, Deserialize)]
+pub struct CheckDefinitions {
+    /// Fields of every check, fields of other check types being ignored
+    #[serde(default)]
+    defaults: Map<String, Value>,
+    /// Fields of the checks extending the template by name
+    #[serde(default)]
+    templates: HashMap<String, Map<String, Value>>,
+    checks: Vec<Map<String, Value>>,
+}
+
+impl From<Vec<Map<String, Value>>> for CheckDefinitions {
+    /// Checks without defaults nor templates, like the ones pulled from brokers
+    fn from(checks: Vec<Map<String, Value>>) -> Self {
+        CheckDefinitions {
+            defaults: Map::new(),
+            templates: HashMap::new(),
+            checks,
+        }
+    }
+}
+
+impl CheckDefinitions {
+    /// Merge the defaults and templates into every check, and parse them
+    pub fn resolve(self, interpolation: Interpolation) -> Result<Vec<Job>, TemplateError> {
+        if let Some(name) = self
+            .templates
+            .iter()
+            .find_map(|(name, template)| template.contains_key(EXTENDS).then_some(name))
+        {
+            return Err(TemplateError::NestedTemplate(name.clone()));
+        }
+
+        let jobs = self
+            .checks
+            .iter()
+            .enumerate()
+            .map(|(index, check)| self.resolve_check(index, check.clone(), interpolation))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut ids: HashMap<JobId, String> = HashMap::new();
+        for job in &jobs {
+            if let Some(first) = ids.insert(job.id(), job.pretty_name()) {
+                return Err(TemplateError::DuplicateId {
+                    id: job.id().to_string(),
+                    first,
+                    second: job.pretty_name(),
+                });
+            }
+        }
+        Ok(jobs)
+    }
+
+    fn resolve_check(
+        &self,
+        index: usize,
+        mut check: Map<String, Value>,
+        interpolation: Interpolation,
+    ) -> Result<Job, TemplateError> {
+        let name = match check.get(PRETTY_NAME) {
+            Some(Value::String(name)) => name.clone(),
+            _ => format!("#{}", index + 1),
+        };
+        let extends = match check.remove(EXTENDS) {
+            None => vec![],
+            Some(Value::String(template)) => vec![template],
+            Some(Value::Array(templates)) => templates
+                .into_iter()
+                .map(|template| match template {
+                    Value::String(template) => Ok(template),
+                    _ => Err(TemplateError::InvalidExtends(name.clone())),
+                })
+                .collect::<Result<_, _>>()?,
+            Some(_) => return Err(TemplateError::InvalidExtends(name)),
+        };
+
+        let mut fields = self.defaults.clone();
+        for template in extends {
+            let Some(template_fields) = self.templates.get(&template) else {
+                return Err(TemplateError::UnknownTemplate {
+                    check: name,
+                    template,
+                });
+            };
+            override_fields(&mut fields, template_fields.clone());
+        }
+        override_fields(&mut fields, check);
+
+        let mut fields = Value::Object(fields);
+        if interpolation == Interpolation::Enabled {
+            if let Err(source) = interpolate_value(&mut fields, &env_var) {
+                return Err(TemplateError::Interpolation {
+                    check: name,
+                    source,
+                });
+            }
+        }
+        if let Value::Object(fields) = &mut fields {
+            if let (None, Some(Value::String(pretty_name))) =
+                (fields.get(ID), fields.get(PRETTY_NAME))
+            {
+                let id = derive_id(pretty_name);
+                fields.insert(ID.to_string(), Value::String(id.to_string()));
+            }
+        }
+        serde_json::from_value(fields).map_err(|source| TemplateError::InvalidCheck {
+            check: name,
+            source,
+        })
+    }
+}
+
+/// ID of a check that doesn't define one, the same for every load of the check
+fn derive_id(pretty_name: &str) -> JobId {
+    let digest = Sha256::digest(pretty_name.as_bytes());
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&digest[..16]);
+    JobId::from(Ulid::from_bytes(bytes))
+}
+
+/// Merge the fields of `overlay` into the inherited `base` ones, its trigger
+/// replacing the inherited one
+fn override_fields(base: &mut Map<String, Value>, overlay: Map<String, Value>) {
+    if TRIGGERS
+        .iter()
+        .any(|trigger| overlay.contains_key(*trigger))
+    {
+        for trigger in TRIGGERS {
+            base.remove(trigger);
+        }
+    }
+    merge(base, overlay);
+}
+
+/// Merge `overlay` into `base`, maps being merged recursively
+fn merge(base: &mut Map<String, Value>, overlay: Map<String, Value>) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Object(base)), Value::Object(value)) => merge(base, value),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jobs::schedule::Schedule;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    fn resolve(definitions: &str) -> Result<Vec<Job>, TemplateError> {
+        serde_yaml::from_str::<CheckDefinitions>(definitions)
+            .unwrap()
+            .resolve(Interpolation::Enabled)
+    }
+
+    #[test]
+    fn test_templates_merged() {
+        let jobs = resolve(
+            r#"
+            defaults:
+              interval: 30
+              timeout: 5
+              headers:
+                user-agent: "isok"
+            templates:
+              internal_api:
+                type: "http"
+                interval: 10
+                headers:
+                  authorization: "Bearer token"
+                assertions:
+                  status_codes: [200]
+              slow:
+                timeout: 20
+            checks:
+              - id: "01ARZ3NDEKTSV4RRWETS2EGZ5A"
+                pretty_name: "payments"
+                extends: ["internal_api", "slow"]
+                endpoint: "https://payments.internal/health"
+                headers:
+                  x-team: "payments"
+              - id: "01ARZ3NDEKTSV4RRWETS2EGZ5B"
+                pretty_name: "search"
+                extends: "internal_api"
+                endpoint: "https://search.internal/health"
+                timeout: null
+              - id: "01ARZ3NDEKTSV4RRWETS2EGZ5C"
+                pretty_name: "redis"
+                type: "tcp"
+                endpoint: "redis.internal:6379"
+                secured: false
+            "#,
+        )
+        .unwrap();
+
+        let expected: Vec<Job> = serde_json::from_value(json!([
+            {
+                "id": "01ARZ3NDEKTSV4RRWETS2EGZ5A",
+                "pretty_name": "payments",
+                "type": "http",
+                "endpoint": "https://payments.internal/health",
+                "interval": 10,
+                "timeout": 20,
+                "headers": {
+                    "user-agent": "isok",
+                    "authorization": "Bearer token",
+                    "x-team": "payments",
+                },
+                "assertions": { "status_codes": [200] },
+            },
+            {
+                "id": "01ARZ3NDEKTSV4RRWETS2EGZ5B",
+                "pretty_name": "search",
+                "type": "http",
+                "endpoint": "https://search.internal/health",
+                "interval": 10,
+                "headers": {
+                    "user-agent": "isok",
+                    "authorization": "Bearer token",
+                },
+                "assertions": { "status_codes": [200] },
+            },
+            {
+                "id": "01ARZ3NDEKTSV4RRWETS2EGZ5C",
+                "pretty_name": "redis",
+                "type": "tcp",
+                "endpoint": "redis.internal:6379",
+                "secured": false,
+                "interval": 30,
+                "timeout": 5,
+            },
+        ]))
+        .unwrap();
+        assert_eq!(jobs, expected);
+    }
+
+    #[test]
+    fn test_schedule_overrides_inherited_interval() {
+        let jobs = resolve(
+            r#"
+            defaults:
+              interval: 10
+            templates:
+              nightly:
+                schedule: "0 2 * * *"
+            checks:
+              - id: "01ARZ3NDEKTSV4RRWETS2EGZ5A"
+                pretty_name: "cron"
+                type: "tcp"
+                endpoint: "127.0.0.1:8080"
+                secured: false
+                schedule: "*/5 * * * *"
+              - id: "01ARZ3NDEKTSV4RRWETS2EGZ5B"
+                pretty_name: "nightly"
+                extends: "nightly"
+                type: "tcp"
+                endpoint: "127.0.0.1:8080"
+                secured: false
+              - id: "01ARZ3NDEKTSV4RRWETS2EGZ5C"
+                pretty_name: "every minute"
+                extends: "nightly"
+                type: "tcp"
+                endpoint: "127.0.0.1:8080"
+                secured: false
+                interval: 1m
+            "#,
+        )
+        .unwrap();
+
+        let schedules: Vec<Schedule> = serde_json::from_value(json!([
+            { "schedule": "*/5 * * * *" },
+            { "schedule": "0 2 * * *" },
+            { "interval": 60 },
+        ]))
+        .unwrap();
+        assert_eq!(
+            jobs.iter().map(Job::schedule).collect::<Vec<_>>(),
+            schedules.iter().collect::<Vec<_>>()
+        );
+
+        // Both set by the check itself are still rejected
+        assert!(matches!(
+            resolve(
+                r#"
+                checks:
+                  - pretty_name: "both"
+                    type: "tcp"
+                    endpoint: "127.0.0.1:8080"
+                    secured: false
+                    interval: 10
+                    schedule: "* * * * *"
+                "#
+            ),
+            Err(TemplateError::InvalidCheck { .. })
+        ));
+    }
+
+    #[test]
+    fn test_derived_ids() {
+        let checks = |second: &str| {
+            format!(
+                r#"
+                defaults: {{ type: "tcp", endpoint: "127.0.0.1:8080", secured: false, interval: 10 }}
+                checks:
+                  - pretty_name: "first"
+                  - pretty_name: "{}"
+                "#,
+                second
+            )
+        };
+        let jobs = resolve(&checks("second")).unwrap();
+        // Derived IDs are the same from one load to another
+        assert_eq!(jobs[0].id(), resolve(&checks("second")).unwrap()[0].id());
+        assert_ne!(jobs[0].id(), jobs[1].id());
+
+        assert!(matches!(
+            resolve(&checks("first")),
+            Err(TemplateError::DuplicateId { first, second, .. })
+                if first == "first" && second == "first"
+        ));
+    }
+
+    #[test]
+    fn test_invalid_templates() {
+        let checks = r#"
+            checks:
+              - pretty_name: "payments"
+                extends: "internal_api"
+                type: "tcp"
+                endpoint: "127.0.0.1:8080"
+                secured: false
+                interval: 10
+            "#;
+        assert!(matches!(
+            resolve(checks),
+            Err(TemplateError::UnknownTemplate { check, template })
+                if check == "payments" && template == "internal_api"
+        ));
+
+        let nested = r#"
+            templates:
+              base: { interval: 10 }
+              internal_api: { extends: "base" }
+            checks: []
+            "#;
+        assert!(matches!(
+            resolve(nested),
+            Err(TemplateError::NestedTemplate(name)) if name == "internal_api"
+        ));
+
+        // Fields are only validated once merged
+        let incomplete = r#"
+            templates:
+              internal_api: { type: "http" }
+            checks:
+              - extends: "internal_api"
+                endpoint: "https://payments.internal/health"
+                interval: 10
+            "#;
+        assert!(matches!(
+            resolve(incomplete),
+            Err(TemplateError::InvalidCheck { check, .. }) if check == "#1"
+        ));
+
+        let missing_secret = r#"
+            checks:
+              - pretty_name: "payments"
+                type: "http"
+                endpoint: "https://payments.internal/health"
+                interval: 10
+                headers:
+                  authorization: "Bearer ${ISOK_TEST_TEMPLATE_MISSING_TOKEN}"
+            "#;
+        assert!(matches!(
+            resolve(missing_secret),
+            Err(TemplateError::Interpolation {
+                check,
+                source: InterpolationError::MissingVariable(_),
+            }) if check == "payments"
+        ));
+        // References of remote checks are kept as is
+        assert!(serde_yaml::from_str::<CheckDefinitions>(missing_secret)
+            .unwrap()
+            .resolve(Interpolation::Disabled)
+            .is_ok());
+    }
+}
@@ -0,0 +1,125 @@
+use std::path::Path;
+use std::sync::{Arc, OnceLock};
+use tokio_rustls::rustls::crypto::{ring, CryptoProvider};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+/// Crypto provider of every TLS connection made by checks
+pub(crate) fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// Root certificates of the system, loaded once
+pub(crate) fn system_roots() -> Result<Arc<RootCertStore>, String> {
+    static ROOTS: OnceLock<Result<Arc<RootCertStore>, String>> = OnceLock::new();
+    ROOTS
+        .get_or_init(|| {
+            let certs = rustls_native_certs::load_native_certs()
+                .map_err(|e| format!("Unable to load system root certificates: {}", e))?;
+            let mut roots = RootCertStore::empty();
+            roots.add_parsable_certificates(certs);
+            Ok(Arc::new(roots))
+        })
+        .clone()
+}
+
+/// Root certificates from a PEM bundle
+pub(crate) fn load_ca_cert(path: &Path) -> Result<RootCertStore, String> {
+    let pem = std::fs::read(path)
+        .map_err(|e| format!("Unable to read CA certificate {}: {}", path.display(), e))?;
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut pem.as_slice()) {
+        let cert = cert.map_err(|e| format!("Invalid CA certificate {}: {}", path.display(), e))?;
+        roots
+            .add(cert)
+            .map_err(|e| format!("Invalid CA certificate {}: {}", path.display(), e))?;
+    }
+    Ok(roots)
+}
+
+/// Root certificates trusted by a check, loaded once from its CA certificate, or
+/// the system roots
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Roots(OnceLock<Arc<RootCertStore>>);
+
+impl Roots {
+    /// Roots of the check, loaded on first use
+    pub(crate) fn get_or_load(&self, ca_cert: Option<&Path>) -> Result<Arc<RootCertStore>, String> {
+        if let Some(roots) = self.0.get() {
+            return Ok(roots.clone());
+        }
+        let roots = match ca_cert {
+            Some(path) => Arc::new(load_ca_cert(path)?),
+            None => system_roots()?,
+        };
+        Ok(self.0.get_or_init(|| roots).clone())
+    }
+}
+
+/// Derived from the job configuration, it doesn't take part in comparisons
+impl PartialEq for Roots {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+/// Client configuration verifying servers against `roots`
+pub(crate) fn client_config(roots: Arc<RootCertStore>) -> Result<ClientConfig, String> {
+    Ok(ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::provider;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::time::SystemTime;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+    use tokio_rustls::rustls::ServerConfig;
+    use tokio_rustls::TlsAcceptor;
+
+    /// Serve TLS with a certificate for `localhost` expiring at `not_after`, signed by
+    /// a CA generated at test time. Returns the port and the path of the CA certificate.
+    pub(crate) async fn serve_tls(dir: &Path, not_after: SystemTime) -> (u16, PathBuf) {
+        let ca_key = rcgen::KeyPair::generate().unwrap();
+        let mut ca_params = rcgen::CertificateParams::new(vec![]).unwrap();
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        ca_params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, "isok test CA");
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let key = rcgen::KeyPair::generate().unwrap();
+        let mut params =
+            rcgen::CertificateParams::new(vec!["localhost".to_string(), "127.0.0.1".to_string()])
+                .unwrap();
+        params.not_after = not_after.into();
+        let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+        let ca_path = dir.join("ca.pem");
+        std::fs::write(&ca_path, ca.pem()).unwrap();
+
+        let config = ServerConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![cert.der().clone(), CertificateDer::from(ca.der().to_vec())],
+                PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der())),
+            )
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let _ = acceptor.accept(stream).await;
+                });
+            }
+        });
+        (port, ca_path)
+    }
+}
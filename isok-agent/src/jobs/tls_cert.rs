@@ -0,0 +1,446 @@
+use crate::batch_sender::JobResult;
+use crate::jobs::{host_and_port, tls, Execute, JobError};
+use async_trait::async_trait;
+use isok_data::broker_rpc::check_result::Details;
+use isok_data::broker_rpc::{CheckJobStatus, JobDetailsTlsCert};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::client::WebPkiServerVerifier;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{
+    CertificateError, ClientConfig, DigitallySignedStruct, Error as TlsError, SignatureScheme,
+};
+use tokio_rustls::TlsConnector;
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::FromDer;
+
+/// Inspect the certificate chain presented by a TLS server. The check fails when the
+/// chain isn't trusted, the certificate isn't valid for the server name, or it expires
+/// in less than `min_days_to_expiry` days.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Clone)]
+pub struct TlsCertJob {
+    /// Host and port to connect to, e.g. `example.com:443`
+    endpoint: String,
+    /// Minimum number of days before the certificate expires
+    #[serde(default = "TlsCertJob::default_min_days_to_expiry")]
+    min_days_to_expiry: i64,
+    /// Server name sent in the TLS handshake and verified against the certificate,
+    /// the host of the endpoint by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sni: Option<String>,
+    /// PEM bundle of the authorities trusted to sign the certificate, the system
+    /// roots are used when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ca_cert: Option<PathBuf>,
+    #[serde(skip)]
+    roots: tls::Roots,
+}
+
+impl TlsCertJob {
+    pub fn new(endpoint: String) -> Self {
+        TlsCertJob {
+            endpoint,
+            min_days_to_expiry: Self::default_min_days_to_expiry(),
+            sni: None,
+            ca_cert: None,
+            roots: tls::Roots::default(),
+        }
+    }
+
+    pub fn with_min_days_to_expiry(mut self, min_days_to_expiry: i64) -> Self {
+        self.min_days_to_expiry = min_days_to_expiry;
+        self
+    }
+
+    pub fn with_sni(mut self, sni: String) -> Self {
+        self.sni = Some(sni);
+        self
+    }
+
+    pub fn with_ca_cert(mut self, ca_cert: PathBuf) -> Self {
+        self.ca_cert = Some(ca_cert);
+        self
+    }
+
+    fn default_min_days_to_expiry() -> i64 {
+        14
+    }
+
+    /// Connect to the server and retrieve its chain, along with the result of its
+    /// verification
+    async fn inspect(
+        &self,
+        host: &str,
+        port: u16,
+    ) -> Result<(Vec<CertificateDer<'static>>, Result<(), TlsError>), String> {
+        let server_name_str = self.sni.as_deref().unwrap_or(host);
+        let server_name = ServerName::try_from(server_name_str.to_string())
+            .map_err(|_| format!("Invalid server name {}", server_name_str))?;
+        let roots = self.roots.get_or_load(self.ca_cert.as_deref())?;
+        let verifier = Arc::new(RecordingVerifier {
+            inner: WebPkiServerVerifier::builder_with_provider(roots, tls::provider())
+                .build()
+                .map_err(|e| format!("Unable to verify certificates: {}", e))?,
+            verification: Mutex::new(None),
+        });
+        let config = ClientConfig::builder_with_provider(tls::provider())
+            .with_safe_default_protocol_versions()
+            .map_err(|e| e.to_string())?
+            .dangerous()
+            .with_custom_certificate_verifier(verifier.clone())
+            .with_no_client_auth();
+
+        let stream = TcpStream::connect((host, port))
+            .await
+            .map_err(|e| format!("Unable to connect to {}: {}", self.endpoint, e))?;
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(server_name, stream)
+            .await
+            .map_err(|e| format!("TLS handshake with {} failed: {}", self.endpoint, e))?;
+        let chain = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .map(|chain| chain.iter().map(|cert| cert.clone().into_owned()).collect())
+            .unwrap_or_default();
+        let verification = verifier
+            .verification
+            .lock()
+            .map_err(|_| "Certificate verification state is poisoned".to_string())?
+            .take()
+            .unwrap_or_else(|| Err(TlsError::General("Certificate wasn't verified".to_string())));
+        Ok((chain, verification))
+    }
+}
+
+/// Verifier recording the outcome of the chain verification instead of aborting the
+/// handshake, so the certificate can be inspected even when it isn't trusted
+#[derive(Debug)]
+struct RecordingVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    verification: Mutex<Option<Result<(), TlsError>>>,
+}
+
+impl ServerCertVerifier for RecordingVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        let verification = self
+            .inner
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+            .map(|_| ());
+        if let Ok(mut state) = self.verification.lock() {
+            *state = Some(verification);
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, TlsError> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// Details of the leaf certificate, `verification` being the outcome of the chain
+/// and hostname verification
+fn certificate_details(
+    leaf: &CertificateDer<'_>,
+    chain_length: usize,
+    verification: Result<(), TlsError>,
+) -> Result<JobDetailsTlsCert, String> {
+    let (_, cert) = X509Certificate::from_der(leaf.as_ref())
+        .map_err(|e| format!("Unable to parse server certificate: {}", e))?;
+
+    let not_after = cert.validity().not_after.timestamp();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let subject_alt_names = cert
+        .subject_alternative_name()
+        .ok()
+        .flatten()
+        .map(|san| {
+            san.value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name) => Some(name.to_string()),
+                    GeneralName::IPAddress(bytes) => ip_address(bytes).map(|ip| ip.to_string()),
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let hostname_matches = !matches!(
+        verification,
+        Err(TlsError::InvalidCertificate(
+            CertificateError::NotValidForName
+        ))
+    );
+    Ok(JobDetailsTlsCert {
+        days_to_expiry: (not_after - now).div_euclid(86400),
+        not_after: Some((UNIX_EPOCH + Duration::from_secs(not_after.max(0) as u64)).into()),
+        issuer: cert.issuer().to_string(),
+        subject_alt_names,
+        // Webpki verifies the chain before the hostname, a hostname mismatch implies
+        // a valid chain
+        chain_valid: verification.is_ok() || !hostname_matches,
+        hostname_matches,
+        chain_length: chain_length as u32,
+        verification_error: verification.err().map(|e| e.to_string()),
+    })
+}
+
+fn ip_address(bytes: &[u8]) -> Option<IpAddr> {
+    match bytes.len() {
+        4 => Some(IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+        16 => Some(IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+        _ => None,
+    }
+}
+
+#[async_trait]
+impl Execute for TlsCertJob {
+    fn prepare(&self) -> Result<(), JobError> {
+        self.roots
+            .get_or_load(self.ca_cert.as_deref())
+            .map(|_| ())
+            .map_err(JobError::InvalidJobConfig)
+    }
+
+    async fn execute(&self, msg: &mut JobResult) -> Result<(), JobError> {
+        let Some((host, port)) = host_and_port(&self.endpoint) else {
+            msg.set_status(CheckJobStatus::Unreachable);
+            msg.set_reason(format!("Invalid endpoint {}", self.endpoint));
+            return Ok(());
+        };
+
+        let start_time = Instant::now();
+        let (chain, verification) = match self.inspect(host, port).await {
+            Ok(inspection) => inspection,
+            Err(reason) => {
+                msg.set_status(CheckJobStatus::Unreachable);
+                msg.set_reason(reason);
+                return Ok(());
+            }
+        };
+        msg.set_latency(start_time.elapsed());
+
+        let Some(leaf) = chain.first() else {
+            msg.set_status(CheckJobStatus::Unreachable);
+            msg.set_reason(format!("{} didn't present any certificate", self.endpoint));
+            return Ok(());
+        };
+        let details = match certificate_details(leaf, chain.len(), verification) {
+            Ok(details) => details,
+            Err(reason) => {
+                msg.set_status(CheckJobStatus::Unreachable);
+                msg.set_reason(reason);
+                return Ok(());
+            }
+        };
+
+        let failure = if !details.hostname_matches {
+            Some("Certificate isn't valid for the server name".to_string())
+        } else if let Some(error) = &details.verification_error {
+            Some(format!("Certificate chain isn't valid: {}", error))
+        } else if details.days_to_expiry < self.min_days_to_expiry {
+            Some(format!(
+                "Certificate expires in {} days, below the minimum of {} days",
+                details.days_to_expiry, self.min_days_to_expiry
+            ))
+        } else {
+            None
+        };
+        match failure {
+            Some(reason) => {
+                msg.set_status(CheckJobStatus::AssertionFailed);
+                msg.set_reason(reason);
+            }
+            None => msg.set_status(CheckJobStatus::Reachable),
+        }
+        msg.set_details(Details::DetailsTlsCert(details));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jobs::tls::testing::serve_tls;
+    use crate::jobs::JobInnerConfig;
+    use isok_data::JobId;
+    use pretty_assertions::assert_eq;
+
+    const DAY: Duration = Duration::from_secs(86400);
+
+    async fn execute(job: TlsCertJob) -> (JobResult, JobDetailsTlsCert) {
+        let mut job_result = JobResult::new(JobId::generate());
+        job.execute(&mut job_result)
+            .await
+            .expect("Expected execution to succeed");
+        let Some(Details::DetailsTlsCert(details)) = job_result.details.clone() else {
+            panic!("Expected TLS certificate details, got {:?}", job_result);
+        };
+        (job_result, details)
+    }
+
+    #[test]
+    fn test_tls_cert_job_serde() {
+        let config: JobInnerConfig = serde_yaml::from_str(
+            r#"
+            type: "tls_cert"
+            endpoint: "example.com:443"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            JobInnerConfig::TlsCert(TlsCertJob::new("example.com:443".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tls_cert_job_valid() {
+        let dir = tempfile::tempdir().unwrap();
+        let (port, ca_cert) = serve_tls(dir.path(), SystemTime::now() + DAY * 60).await;
+        let job = TlsCertJob::new(format!("localhost:{}", port)).with_ca_cert(ca_cert);
+
+        let (job_result, details) = execute(job).await;
+        assert_eq!(job_result.status, CheckJobStatus::Reachable);
+        assert!((59..=60).contains(&details.days_to_expiry));
+        assert_eq!(details.issuer, "CN=isok test CA");
+        assert_eq!(details.subject_alt_names, vec!["localhost", "127.0.0.1"]);
+        assert!(details.chain_valid);
+        assert!(details.hostname_matches);
+        assert_eq!(details.chain_length, 2);
+        assert_eq!(details.verification_error, None);
+    }
+
+    #[tokio::test]
+    async fn test_tls_cert_job_expiring_soon() {
+        let dir = tempfile::tempdir().unwrap();
+        let (port, ca_cert) = serve_tls(dir.path(), SystemTime::now() + DAY * 5).await;
+        let job = TlsCertJob::new(format!("127.0.0.1:{}", port))
+            .with_ca_cert(ca_cert)
+            .with_min_days_to_expiry(7);
+
+        let (job_result, details) = execute(job).await;
+        assert_eq!(job_result.status, CheckJobStatus::AssertionFailed);
+        assert!((4..=5).contains(&details.days_to_expiry));
+        assert!(job_result
+            .reason
+            .is_some_and(|reason| reason.starts_with("Certificate expires in")));
+    }
+
+    #[tokio::test]
+    async fn test_tls_cert_job_hostname_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let (port, ca_cert) = serve_tls(dir.path(), SystemTime::now() + DAY * 60).await;
+        let job = TlsCertJob::new(format!("127.0.0.1:{}", port))
+            .with_ca_cert(ca_cert)
+            .with_sni("example.com".to_string());
+
+        let (job_result, details) = execute(job).await;
+        assert_eq!(job_result.status, CheckJobStatus::AssertionFailed);
+        assert!(details.chain_valid);
+        assert!(!details.hostname_matches);
+        assert_eq!(
+            job_result.reason,
+            Some("Certificate isn't valid for the server name".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tls_cert_job_untrusted_chain() {
+        let dir = tempfile::tempdir().unwrap();
+        let (port, _) = serve_tls(dir.path(), SystemTime::now() + DAY * 60).await;
+        let other_dir = tempfile::tempdir().unwrap();
+        let (_, other_ca_cert) = serve_tls(other_dir.path(), SystemTime::now() + DAY * 60).await;
+        let job = TlsCertJob::new(format!("localhost:{}", port)).with_ca_cert(other_ca_cert);
+
+        let (job_result, details) = execute(job).await;
+        assert_eq!(job_result.status, CheckJobStatus::AssertionFailed);
+        assert!(!details.chain_valid);
+        assert!(details.hostname_matches);
+        assert!(details.verification_error.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_tls_cert_job_ca_cert_loaded_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let (port, ca_cert) = serve_tls(dir.path(), SystemTime::now() + DAY * 60).await;
+        let job = TlsCertJob::new(format!("localhost:{}", port)).with_ca_cert(ca_cert.clone());
+        job.prepare()
+            .expect("Expected the CA certificate to be loaded");
+        std::fs::remove_file(ca_cert).unwrap();
+
+        let (job_result, details) = execute(job).await;
+        assert_eq!(job_result.status, CheckJobStatus::Reachable);
+        assert!(details.chain_valid);
+
+        let unreadable = TlsCertJob::new("localhost:443".to_string())
+            .with_ca_cert("/nonexistent/isok/ca.pem".into());
+        assert!(matches!(
+            unreadable.prepare(),
+            Err(JobError::InvalidJobConfig(reason)) if reason.starts_with("Unable to read CA certificate")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_tls_cert_job_invalid_endpoint() {
+        let mut job_result = JobResult::new(JobId::generate());
+        TlsCertJob::new("[::1:443".to_string())
+            .execute(&mut job_result)
+            .await
+            .expect("Expected execution to succeed");
+        assert_eq!(job_result.status, CheckJobStatus::Unreachable);
+        assert_eq!(
+            job_result.reason,
+            Some("Invalid endpoint [::1:443".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tls_cert_job_unreachable() {
+        let mut job_result = JobResult::new(JobId::generate());
+        TlsCertJob::new("127.0.0.1:65534".to_string())
+            .execute(&mut job_result)
+            .await
+            .expect("Expected execution to succeed");
+        assert_eq!(job_result.status, CheckJobStatus::Unreachable);
+        assert_eq!(job_result.details, None);
+    }
+}
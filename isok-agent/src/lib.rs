@@ -1,23 +1,62 @@
 use crate::batch_sender::BatchSender;
 use crate::config::{Config, GetJobsRegistry};
 use crate::errors::{Error, Result};
+use std::future::Future;
 use tokio::join;
+use tokio::signal::unix::{signal, SignalKind};
 
 mod batch_sender;
+mod broker_checks;
+mod check_files;
 pub mod config;
 pub mod errors;
+mod http_checks;
 pub mod jobs;
 mod registry;
+mod reload;
 mod state;
 
+/// Run the agent until it receives SIGINT or SIGTERM
 pub async fn run(config: Config) -> Result<()> {
+    run_until(config, shutdown_signal()).await
+}
+
+/// Run the agent until `shutdown` completes. No check is scheduled past that point,
+/// checks already running are awaited and their results delivered before returning.
+pub async fn run_until(config: Config, shutdown: impl Future<Output = ()>) -> Result<()> {
     let registry = config.get_jobs_registry()?;
+    let check_source = reload::CheckSource::new(config.check_config_adapter).await?;
 
     let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
     let mut batch_sender = BatchSender::new(config.result_sender_adapter, rx)
         .await
-        .map_err(|e| Error::UnableToCreateBatchSender(e))?;
-    join!(registry.execute(tx), batch_sender.run());
+        .map_err(Error::UnableToCreateBatchSender)?;
+    let scheduler = async move {
+        tokio::select! {
+            _ = registry.execute(tx, config.scheduler) => {}
+            _ = reload::watch_checks(check_source, &registry) => {}
+            _ = shutdown => tracing::info!("Shutting down, waiting for running checks to complete"),
+        }
+    };
+    join!(scheduler, batch_sender.run());
 
     Ok(())
 }
+
+async fn shutdown_signal() {
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                tracing::warn!("Unable to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+}
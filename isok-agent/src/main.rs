@@ -9,14 +9,6 @@ use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{fmt, EnvFilter};
 
-#[derive(thiserror::Error, Debug)]
-enum Error {
-    #[error("Unable to load config file: {0}")]
-    UnableToLoadConfigFile(#[from] clap::Error),
-    #[error("Unable to run agent: {0}")]
-    UnableToRunAgent(#[from] isok_agent::errors::Error),
-}
-
 #[derive(Parser, Serialize, Deserialize, Debug)]
 #[command(version, about, long_about = None)]
 struct CliArgs {
@@ -1,50 +1,75 @@
 use crate::batch_sender::JobResult;
-use crate::errors::Result;
+use crate::config::SchedulerConfig;
+use crate::errors::{Error, Result};
+use crate::jobs::template::{CheckDefinitions, Interpolation};
 use crate::jobs::Job;
 use crate::state::JobState;
+use chrono::Utc;
 use dashmap::DashMap;
 use figment::providers::{Format, Yaml};
-use figment::Figment;
-use isok_data::JobPrettyName;
-use serde::Deserialize;
-use std::path::PathBuf;
+use figment::{Figment, Provider};
+use isok_data::JobId;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::mpsc::UnboundedSender;
-use tokio::time::Instant;
+use tokio::sync::Semaphore;
+use tokio::time::{Instant, MissedTickBehavior};
 
 pub struct JobRegistry {
-    jobs: DashMap<JobPrettyName, JobState>,
+    jobs: DashMap<JobId, JobState>,
 }
 
-impl JobRegistry {
-    pub fn from_configuration_file(path_buf: PathBuf) -> Result<JobRegistry> {
-        #[derive(Debug, Deserialize)]
-        struct Wrapper {
-            checks: Vec<Job>,
-        }
-
-        let mut registry = JobRegistry::new();
+/// Changes applied to the registry by a reload
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct RegistryDiff {
+    pub(crate) added: Vec<String>,
+    pub(crate) removed: Vec<String>,
+    pub(crate) updated: Vec<String>,
+}
 
-        let jobs = Figment::new()
-            .merge(Yaml::file(path_buf))
-            .extract::<Wrapper>()?;
+impl RegistryDiff {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.updated.is_empty()
+    }
+}
 
-        for job in jobs.checks {
-            registry.append(job);
-        }
+/// Name of a job in logs, along with the file it was loaded from
+fn describe(job: &Job) -> String {
+    match job.source() {
+        Some(source) => format!("{} ({}, {})", job.pretty_name(), job.id(), source.display()),
+        None => format!("{} ({})", job.pretty_name(), job.id()),
+    }
+}
 
-        Ok(registry)
+impl JobRegistry {
+    pub fn from_configuration_file(path_buf: PathBuf) -> Result<JobRegistry> {
+        Self::from_static_config(Self::read_configuration_file(path_buf)?)
     }
 
     pub fn from_static_config(jobs: Vec<Job>) -> Result<JobRegistry> {
         let mut registry = JobRegistry::new();
 
         for job in jobs {
-            registry.append(job);
+            registry.append(job)?;
         }
 
         Ok(registry)
     }
+
+    pub(crate) fn read_configuration_file(path_buf: PathBuf) -> Result<Vec<Job>> {
+        Self::read_configuration(Yaml::file(path_buf), Interpolation::Enabled)
+    }
+
+    /// Read the `checks` of a configuration document, merged with its defaults and templates
+    pub(crate) fn read_configuration(
+        provider: impl Provider,
+        interpolation: Interpolation,
+    ) -> Result<Vec<Job>> {
+        let definitions: CheckDefinitions = Figment::new().merge(provider).extract()?;
+        Ok(definitions.resolve(interpolation)?)
+    }
 }
 
 impl JobRegistry {
@@ -54,26 +79,303 @@ impl JobRegistry {
         }
     }
 
-    fn append(&mut self, job: Job) {
-        self.jobs
-            .insert(JobPrettyName::new(job.pretty_name()), JobState::new(job));
+    fn append(&mut self, job: Job) -> Result<()> {
+        Self::prepare(&job)?;
+        self.jobs.insert(job.id(), JobState::new(job));
+        Ok(())
+    }
+
+    fn prepare(job: &Job) -> Result<()> {
+        job.prepare().map_err(|source| Error::InvalidCheck {
+            pretty_name: job.pretty_name(),
+            file: job.source().map(Path::to_path_buf),
+            source,
+        })
+    }
+
+    /// Check every job of `jobs` is valid, before they're registered or stored
+    pub(crate) fn validate(jobs: &[Job]) -> Result<()> {
+        jobs.iter().try_for_each(Self::prepare)
+    }
+
+    /// Replace the jobs of the registry by `jobs`, matching them by ID. New jobs are
+    /// scheduled right away, updated jobs keep their schedule and removed jobs are
+    /// no longer scheduled, their running execution being left to complete.
+    /// When any of the jobs is invalid, the registry is left untouched.
+    pub(crate) fn reload(&self, jobs: Vec<Job>) -> Result<RegistryDiff> {
+        Self::validate(&jobs)?;
+
+        let mut diff = RegistryDiff::default();
+        let ids: HashSet<JobId> = jobs.iter().map(Job::id).collect();
+        self.jobs.retain(|id, state| {
+            let keep = ids.contains(id);
+            if !keep {
+                diff.removed.push(describe(state));
+            }
+            keep
+        });
+        for job in jobs {
+            match self.jobs.get_mut(&job.id()) {
+                Some(mut state) if **state != job => {
+                    diff.updated.push(describe(&job));
+                    state.update(job);
+                }
+                Some(_) => {}
+                None => {
+                    diff.added.push(describe(&job));
+                    self.jobs.insert(job.id(), JobState::new(job));
+                }
+            }
+        }
+        Ok(diff)
     }
 
-    pub(crate) async fn execute(&self, tx: UnboundedSender<JobResult>) {
+    /// Interval at which the registry looks for jobs to be executed
+    const TICK_INTERVAL: Duration = Duration::from_millis(100);
+
+    /// Run the scheduling loop forever. Every due job is spawned as its own task, so
+    /// a slow check never delays the others. At most `max_concurrent_jobs` jobs are
+    /// executing at the same time, further jobs wait for a slot to be released.
+    pub(crate) async fn execute(&self, tx: UnboundedSender<JobResult>, scheduler: SchedulerConfig) {
+        let semaphore = Arc::new(Semaphore::new(scheduler.max_concurrent_jobs.max(1)));
+        let default_timeout = scheduler.default_timeout;
+        let mut ticker = tokio::time::interval(Self::TICK_INTERVAL);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
         loop {
+            ticker.tick().await;
             let current_time = Instant::now();
 
             for mut job in self.jobs.iter_mut() {
                 if job.next_run() > current_time {
                     continue;
                 }
-                let next_interval = current_time + job.interval();
+                job.set_next_run(current_time);
+
+                if !job.schedule().is_active(Utc::now()) {
+                    tracing::debug!(job_name = ?job.key(), "Job outside of its active windows, skipping");
+                    continue;
+                }
+
+                if job.is_running() {
+                    tracing::warn!(job_name = ?job.key(), "Previous job execution is still running, skipping");
+                    continue;
+                }
 
                 tracing::debug!(job_name = ?job.key(), "Job execution");
-                job.set_next_run(next_interval);
-                job.execute(tx.clone()).await.unwrap();
+                let inner = job.job();
+                let timeout = inner.timeout_or(default_timeout);
+                let tx = tx.clone();
+                let semaphore = semaphore.clone();
+                job.set_execution(tokio::spawn(async move {
+                    // The semaphore is never closed, acquiring can't fail
+                    let Ok(_permit) = semaphore.acquire_owned().await else {
+                        return;
+                    };
+                    if let Err(e) = inner.execute(tx, timeout).await {
+                        tracing::error!(job_id = %inner.id(), "Job execution failed: {}", e);
+                    }
+                }));
             }
-            tokio::time::sleep(Duration::from_millis(100)).await;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::jobs::http::HttpJob;
+    use crate::jobs::tcp::TcpJob;
+    use crate::jobs::JobInnerConfig;
+    use isok_data::broker_rpc::CheckJobStatus;
+
+    // A job hanging forever must not prevent other jobs from running
+    #[tokio::test]
+    async fn test_execute_hung_job_does_not_block_others() {
+        // Accept connections but never answer, any HTTP request will hang
+        let hung_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let hung_port = hung_listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut streams = vec![];
+            loop {
+                let (stream, _) = hung_listener.accept().await.unwrap();
+                streams.push(stream);
+            }
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let hung_job = Job::new(
+            Duration::from_secs(60),
+            JobInnerConfig::Http(HttpJob::new(format!("http://127.0.0.1:{}", hung_port))),
+            "hung".to_string(),
+        );
+        let tcp_job = Job::new(
+            Duration::from_secs(60),
+            JobInnerConfig::Tcp(TcpJob::new(format!("127.0.0.1:{}", port))),
+            "tcp".to_string(),
+        );
+        let tcp_job_id = tcp_job.id();
+        let registry = JobRegistry::from_static_config(vec![hung_job, tcp_job]).unwrap();
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let scheduler = SchedulerConfig {
+            max_concurrent_jobs: 10,
+            ..Default::default()
+        };
+        tokio::spawn(async move { registry.execute(tx, scheduler).await });
+
+        let result = tokio::time::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .expect("Expected a result while the other job hangs")
+            .unwrap();
+        assert_eq!(result.id, tcp_job_id);
+        assert_eq!(result.status, CheckJobStatus::Reachable);
+    }
+
+    #[test]
+    fn test_reload() {
+        let tcp_job = |name: &str| {
+            Job::new(
+                Duration::from_secs(60),
+                JobInnerConfig::Tcp(TcpJob::new("127.0.0.1:8080".to_string())),
+                name.to_string(),
+            )
+        };
+        let kept = tcp_job("kept");
+        let updated = tcp_job("updated");
+        let removed = tcp_job("removed");
+        let registry =
+            JobRegistry::from_static_config(vec![kept.clone(), updated.clone(), removed.clone()])
+                .unwrap();
+        registry
+            .jobs
+            .get_mut(&updated.id())
+            .unwrap()
+            .set_next_run(Instant::now());
+        let next_run = registry.jobs.get(&updated.id()).unwrap().next_run();
+
+        let updated = updated.with_timeout(Duration::from_secs(3));
+        let added = tcp_job("added");
+        let diff = registry
+            .reload(vec![kept.clone(), updated.clone(), added.clone()])
+            .unwrap();
+        assert_eq!(
+            diff,
+            RegistryDiff {
+                added: vec![describe(&added)],
+                removed: vec![describe(&removed)],
+                updated: vec![describe(&updated)],
+            }
+        );
+        assert_eq!(registry.jobs.len(), 3);
+        let state = registry.jobs.get(&updated.id()).unwrap();
+        assert_eq!(*state.job(), updated);
+        // Updated jobs keep their schedule
+        assert_eq!(state.next_run(), next_run);
+        drop(state);
+
+        // Reloading the same jobs changes nothing
+        assert!(registry
+            .reload(vec![kept.clone(), updated.clone(), added.clone()])
+            .unwrap()
+            .is_empty());
+    }
+
+    // Checks without an ID are matched across reloads by their name
+    #[test]
+    fn test_reload_without_id() {
+        let read = |endpoint: &str| {
+            JobRegistry::read_configuration(
+                Yaml::string(&format!(
+                    r#"
+                checks:
+                  - type: "tcp"
+                    pretty_name: "no id"
+                    endpoint: "{}"
+                    secured: false
+                    interval: 60
+                "#,
+                    endpoint
+                )),
+                Interpolation::Enabled,
+            )
+            .unwrap()
+        };
+        let registry = JobRegistry::from_static_config(read("127.0.0.1:8080")).unwrap();
+        let id = read("127.0.0.1:8080")[0].id();
+        registry
+            .jobs
+            .get_mut(&id)
+            .unwrap()
+            .set_next_run(Instant::now());
+        let next_run = registry.jobs.get(&id).unwrap().next_run();
+
+        assert!(registry.reload(read("127.0.0.1:8080")).unwrap().is_empty());
+        let updated = read("127.0.0.1:8081");
+        let diff = registry.reload(updated.clone()).unwrap();
+        assert_eq!(
+            diff,
+            RegistryDiff {
+                updated: vec![describe(&updated[0])],
+                ..Default::default()
+            }
+        );
+        assert_eq!(registry.jobs.len(), 1);
+        assert_eq!(registry.jobs.get(&id).unwrap().next_run(), next_run);
+    }
+
+    #[test]
+    fn test_invalid_reload_keeps_previous_jobs() {
+        let job = Job::new(
+            Duration::from_secs(60),
+            JobInnerConfig::Tcp(TcpJob::new("127.0.0.1:8080".to_string())),
+            "tcp".to_string(),
+        );
+        let registry = JobRegistry::from_static_config(vec![job.clone()]).unwrap();
+
+        let invalid: Job = serde_yaml::from_str(
+            r#"
+            type: "http"
+            pretty_name: "invalid header"
+            endpoint: "http://127.0.0.1:8080"
+            interval: 10
+            headers:
+              "invalid header": "value"
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(
+            registry.reload(vec![invalid]),
+            Err(Error::InvalidCheck { .. })
+        ));
+        assert_eq!(registry.jobs.len(), 1);
+        assert!(registry.jobs.contains_key(&job.id()));
+    }
+
+    #[test]
+    fn test_invalid_check_rejected_at_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("checks.yaml");
+        std::fs::write(
+            &path,
+            r#"
+            checks:
+              - type: "http"
+                pretty_name: "invalid header"
+                endpoint: "http://127.0.0.1:8080"
+                interval: 10
+                headers:
+                  "invalid header": "value"
+            "#,
+        )
+        .unwrap();
+
+        let Err(Error::InvalidCheck { pretty_name, .. }) =
+            JobRegistry::from_configuration_file(path)
+        else {
+            panic!("Expected the check to be rejected");
+        };
+        assert_eq!(pretty_name, "invalid header");
+    }
+}
@@ -0,0 +1,160 @@
+use crate::broker_checks::BrokerChecks;
+use crate::config::{ConfigCheckAdapter, FileConfigCheckAdapter, StaticConfigAdapter};
+use crate::errors::Result;
+use crate::http_checks::HttpChecks;
+use crate::jobs::Job;
+use crate::registry::JobRegistry;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::MissedTickBehavior;
+
+/// Delay between a change of the checks source and their reload, so a file written
+/// in several steps is only reloaded once
+const DEBOUNCE: Duration = Duration::from_millis(250);
+
+/// Source the checks are reloaded from
+pub(crate) enum CheckSource {
+    Static(StaticConfigAdapter),
+    File(FileConfigCheckAdapter),
+    Broker(BrokerChecks),
+    Http(HttpChecks),
+}
+
+impl CheckSource {
+    pub(crate) async fn new(adapter: ConfigCheckAdapter) -> Result<Self> {
+        Ok(match adapter {
+            ConfigCheckAdapter::Static(adapter) => CheckSource::Static(adapter),
+            ConfigCheckAdapter::File(adapter) => CheckSource::File(adapter),
+            ConfigCheckAdapter::Broker(adapter) => {
+                CheckSource::Broker(BrokerChecks::new(&adapter).await?)
+            }
+            ConfigCheckAdapter::Http(adapter) => CheckSource::Http(HttpChecks::new(&adapter)?),
+        })
+    }
+
+    async fn load(&mut self) -> Result<Vec<Job>> {
+        match self {
+            CheckSource::Static(adapter) => Ok(adapter.checks.clone()),
+            CheckSource::File(adapter) => adapter.load_checks(),
+            CheckSource::Broker(broker_checks) => broker_checks.pull().await,
+            CheckSource::Http(http_checks) => http_checks.pull().await,
+        }
+    }
+
+    /// Interval at which checks are pulled, for sources that can't notify changes
+    fn poll_interval(&self) -> Option<Duration> {
+        match self {
+            CheckSource::Broker(broker_checks) => Some(broker_checks.poll_interval()),
+            CheckSource::Http(http_checks) => Some(http_checks.poll_interval()),
+            CheckSource::Static(_) | CheckSource::File(_) => None,
+        }
+    }
+}
+
+/// Reload the checks of `registry` whenever `source` changes or the agent receives
+/// SIGHUP. An invalid source is rejected, the previous checks keep running.
+pub(crate) async fn watch_checks(mut source: CheckSource, registry: &JobRegistry) {
+    let (tx, mut changes) = tokio::sync::mpsc::unbounded_channel();
+    let _watcher = match &source {
+        CheckSource::File(adapter) => adapter
+            .watch(tx)
+            .map_err(|e| {
+                tracing::warn!(
+                    "Unable to watch checks, they are only reloaded on SIGHUP: {}",
+                    e
+                )
+            })
+            .ok(),
+        CheckSource::Static(_) | CheckSource::Broker(_) | CheckSource::Http(_) => None,
+    };
+    let mut hangup = signal(SignalKind::hangup())
+        .map_err(|e| tracing::warn!("Unable to listen for SIGHUP: {}", e))
+        .ok();
+    // The first tick completes immediately, checks are pulled as soon as the agent starts
+    let mut poll = source.poll_interval().map(|period| {
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        interval
+    });
+
+    loop {
+        let received_hangup = async {
+            match hangup.as_mut() {
+                Some(hangup) => hangup.recv().await,
+                None => std::future::pending().await,
+            }
+        };
+        let poll_tick = async {
+            match poll.as_mut() {
+                Some(poll) => {
+                    poll.tick().await;
+                }
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            Some(()) = received_hangup => tracing::info!("Received SIGHUP, reloading checks"),
+            Some(()) = changes.recv() => {
+                tokio::time::sleep(DEBOUNCE).await;
+                while changes.try_recv().is_ok() {}
+                tracing::info!("Checks changed, reloading them");
+            }
+            _ = poll_tick => tracing::debug!("Pulling checks"),
+        }
+        reload(&mut source, registry).await;
+    }
+}
+
+async fn reload(source: &mut CheckSource, registry: &JobRegistry) {
+    match source.load().await.and_then(|jobs| registry.reload(jobs)) {
+        Ok(diff) if diff.is_empty() => tracing::debug!("Checks reloaded, nothing changed"),
+        Ok(diff) => tracing::info!(
+            added = ?diff.added,
+            removed = ?diff.removed,
+            updated = ?diff.updated,
+            "Checks reloaded"
+        ),
+        Err(e) => tracing::error!(
+            error = ?e,
+            "Unable to reload checks, the previous ones keep running"
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_file_change_notified() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("checks.yaml");
+        std::fs::write(&path, "checks: []").unwrap();
+        let ConfigCheckAdapter::File(adapter) = serde_yaml::from_str(&format!(
+            r#"{{ name: "file", path: "{}" }}"#,
+            path.display()
+        ))
+        .unwrap() else {
+            panic!("Expected a file adapter");
+        };
+
+        let (tx, mut changes) = tokio::sync::mpsc::unbounded_channel();
+        let _watcher = adapter.watch(tx).unwrap();
+        // Other files of the directory are ignored
+        std::fs::write(dir.path().join("other.yaml"), "checks: []").unwrap();
+        assert!(
+            tokio::time::timeout(Duration::from_millis(500), changes.recv())
+                .await
+                .is_err()
+        );
+
+        // Editors commonly replace the file with a renamed one
+        let replacement = dir.path().join(".checks.yaml.swp");
+        std::fs::write(&replacement, "checks: []").unwrap();
+        std::fs::rename(&replacement, &path).unwrap();
+        tokio::time::timeout(Duration::from_secs(2), changes.recv())
+            .await
+            .expect("Expected the file change to be notified")
+            .unwrap();
+    }
+}
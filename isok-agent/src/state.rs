@@ -1,33 +1,83 @@
+use crate::jobs::schedule::Schedule;
 use crate::jobs::Job;
+use chrono::{DateTime, Utc};
 use std::ops::Deref;
+use std::sync::Arc;
 use std::time::Duration;
+use tokio::task::JoinHandle;
 use tokio::time::Instant;
 
 // Own a job and it's last execution
 #[derive(Debug)]
 pub struct JobState {
-    job: Job,
+    job: Arc<Job>,
     next_run: Instant,
+    /// Handle of the last spawned execution, used to prevent a job from
+    /// being executed twice at the same time.
+    execution: Option<JoinHandle<()>>,
 }
 
 impl JobState {
+    /// Delay before the schedule of a job is evaluated again when it has no next run
+    const UNSCHEDULED_RETRY: Duration = Duration::from_secs(3600);
+
     pub(crate) fn new(job: Job) -> JobState {
-        JobState {
+        let mut state = JobState {
             next_run: Instant::now(),
-            job,
-        }
+            job: Arc::new(job),
+            execution: None,
+        };
+        state.schedule(Instant::now(), Utc::now(), Schedule::first_run);
+        state
     }
 
     pub fn next_run(&self) -> Instant {
         self.next_run
     }
 
-    pub fn interval(&self) -> Duration {
-        self.job.interval()
+    pub fn set_next_run(&mut self, current_time: Instant) {
+        self.schedule(current_time, Utc::now(), Schedule::next_run);
+    }
+
+    /// Set the next run from the wall clock time the schedule gives, `now` being
+    /// the wall clock time at `current_time`
+    fn schedule(
+        &mut self,
+        current_time: Instant,
+        now: DateTime<Utc>,
+        next: fn(&Schedule, DateTime<Utc>) -> Option<DateTime<Utc>>,
+    ) {
+        self.next_run = match next(self.job.schedule(), now) {
+            Some(next_run) => current_time + (next_run - now).to_std().unwrap_or_default(),
+            None => {
+                tracing::warn!(job_id = %self.job.id(), "Job has no run within its active windows");
+                current_time + Self::UNSCHEDULED_RETRY
+            }
+        };
     }
 
-    pub fn set_next_run(&mut self, current_time: Instant) {
-        self.next_run = current_time + self.interval();
+    /// Replace the job definition, keeping its running execution. Its schedule is kept
+    /// as well, unless it changed.
+    pub(crate) fn update(&mut self, job: Job) {
+        let rescheduled = job.schedule() != self.job.schedule();
+        self.job = Arc::new(job);
+        if rescheduled {
+            self.schedule(Instant::now(), Utc::now(), Schedule::first_run);
+        }
+    }
+
+    pub(crate) fn job(&self) -> Arc<Job> {
+        self.job.clone()
+    }
+
+    pub(crate) fn is_running(&self) -> bool {
+        self.execution
+            .as_ref()
+            .is_some_and(|handle| !handle.is_finished())
+    }
+
+    pub(crate) fn set_execution(&mut self, handle: JoinHandle<()>) {
+        self.execution = Some(handle);
     }
 }
 
@@ -37,3 +87,54 @@ impl Deref for JobState {
         &self.job
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn job_state(schedule: &str) -> JobState {
+        let job: Job = serde_yaml::from_str(&format!(
+            r#"{{ type: "tcp", pretty_name: "tcp", endpoint: "127.0.0.1:8080", secured: false, {} }}"#,
+            schedule
+        ))
+        .unwrap();
+        JobState::new(job)
+    }
+
+    fn utc(datetime: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(datetime).unwrap().to_utc()
+    }
+
+    #[test]
+    fn test_next_run_from_schedule() {
+        let current_time = Instant::now();
+        let now = utc("2025-01-06T10:02:30Z");
+
+        let mut cron = job_state(r#"schedule: "*/5 * * * *""#);
+        cron.schedule(current_time, now, Schedule::first_run);
+        assert_eq!(cron.next_run(), current_time + Duration::from_secs(150));
+        cron.schedule(
+            current_time + Duration::from_secs(150),
+            utc("2025-01-06T10:05:00Z"),
+            Schedule::next_run,
+        );
+        assert_eq!(cron.next_run(), current_time + Duration::from_secs(450));
+
+        // Paused until its window opens
+        let mut paused =
+            job_state(r#"interval: 1, active_windows: [{ start: "12:00", end: "13:00" }]"#);
+        paused.schedule(current_time, now, Schedule::first_run);
+        assert_eq!(
+            paused.next_run(),
+            current_time + Duration::from_secs(3600 + 57 * 60 + 30)
+        );
+
+        // Never within its window
+        let mut never = job_state(
+            r#"schedule: "0 12 * * *", active_windows: [{ start: "13:00", end: "14:00" }]"#,
+        );
+        never.schedule(current_time, now, Schedule::next_run);
+        assert_eq!(never.next_run(), current_time + JobState::UNSCHEDULED_RETRY);
+    }
+}
@@ -1,10 +1,34 @@
+use crate::auth::{AgentAuthenticator, AuthError};
+use crate::checks::CheckStore;
+use crate::config::{ApiConfig, ApiTlsConfig};
 use crate::message_broker::{MessageBroker, MessageBrokerSender};
 use isok_data::broker_rpc::broker_server::{Broker, BrokerServer};
-use isok_data::broker_rpc::{CheckBatchRequest, CheckBatchResponse, HealthRequest, HealthResponse};
-use tonic::transport::Server;
+use isok_data::broker_rpc::{
+    CheckBatchRequest, CheckBatchResponse, GetChecksRequest, GetChecksResponse, HealthRequest,
+    HealthResponse, Tags,
+};
+use std::path::Path;
+use std::time::SystemTime;
+use tonic::transport::{Certificate, Identity, Server, ServerTlsConfig};
 
 pub(crate) struct BrokerGrpcService {
     message_broker: MessageBroker,
+    authenticator: Option<AgentAuthenticator>,
+    /// Checks served to agents, when configured
+    check_store: Option<CheckStore>,
+}
+
+impl BrokerGrpcService {
+    fn authorize<T>(
+        &self,
+        request: &tonic::Request<T>,
+        tags: Option<&Tags>,
+    ) -> Result<(), AuthError> {
+        match &self.authenticator {
+            Some(authenticator) => authenticator.authorize(request, tags),
+            None => Ok(()),
+        }
+    }
 }
 
 #[tonic::async_trait]
@@ -21,6 +45,7 @@ impl Broker for BrokerGrpcService {
         } else {
             return Err(tonic::Status::invalid_argument("Missing tags"));
         }
+        self.authorize(&request, request.get_ref().tags.as_ref())?;
 
         tracing::debug!(
             "Received a new batch of events, length: {}",
@@ -28,26 +53,67 @@ impl Broker for BrokerGrpcService {
         );
 
         let tags = request.get_ref().tags.clone();
+        let received_at: Option<prost_types::Timestamp> = Some(SystemTime::now().into());
         request.get_mut().events.iter_mut().for_each(|event| {
             event.tags = tags.clone();
+            event.received_at = received_at;
         });
 
         // This piece of code implies that if one event of the batch fails,
         // the agent might duplicate whole batch to another broker. We should
         // treat a batch like a transaction.
-        for event in request.get_ref().events.iter() {
-            self.message_broker
-                .process_message(event)
-                .await
-                .map_err(|e| tonic::Status::internal(e.to_string()))?;
-        }
+        self.message_broker
+            .process_batch(&request.get_ref().events)
+            .await
+            .map_err(|e| tonic::Status::internal(e.to_string()))?;
         Ok(tonic::Response::new(CheckBatchResponse { error: None }))
     }
     async fn health(
         &self,
-        _: tonic::Request<HealthRequest>,
+        request: tonic::Request<HealthRequest>,
     ) -> Result<tonic::Response<HealthResponse>, tonic::Status> {
-        Ok(tonic::Response::new(HealthResponse { healthy: true }))
+        self.authorize(&request, None)?;
+        Ok(tonic::Response::new(HealthResponse {
+            healthy: self.message_broker.health_check().await.is_ok(),
+        }))
+    }
+
+    #[tracing::instrument(skip(self, request), fields(agent_id = tracing::field::Empty, zone = tracing::field::Empty, region = tracing::field::Empty))]
+    async fn get_checks(
+        &self,
+        request: tonic::Request<GetChecksRequest>,
+    ) -> Result<tonic::Response<GetChecksResponse>, tonic::Status> {
+        let Some(tags) = &request.get_ref().tags else {
+            return Err(tonic::Status::invalid_argument("Missing tags"));
+        };
+        tracing::Span::current().record("agent_id", &tags.agent_id);
+        tracing::Span::current().record("zone", &tags.zone);
+        tracing::Span::current().record("region", &tags.region);
+        self.authorize(&request, Some(tags))?;
+
+        let Some(check_store) = &self.check_store else {
+            return Err(tonic::Status::unimplemented(
+                "The broker doesn't serve any check",
+            ));
+        };
+        let checks = check_store.checks_for(tags).await.map_err(|e| {
+            tracing::error!("Unable to serve checks: {}", e);
+            tonic::Status::internal(e.to_string())
+        })?;
+
+        if request.get_ref().version.as_ref() == Some(&checks.version) {
+            return Ok(tonic::Response::new(GetChecksResponse {
+                version: checks.version,
+                unchanged: true,
+                checks: vec![],
+            }));
+        }
+        tracing::debug!("Serving {} checks", checks.checks.len());
+        Ok(tonic::Response::new(GetChecksResponse {
+            version: checks.version,
+            unchanged: false,
+            checks: checks.checks,
+        }))
     }
 }
 
@@ -55,20 +121,53 @@ impl Broker for BrokerGrpcService {
 pub enum ApiError {
     #[error("Unable to bind to address {0}")]
     ServerFailure(#[from] tonic::transport::Error),
+    #[error("Invalid TLS configuration: {0}")]
+    InvalidTlsConfiguration(String),
 }
 
 impl BrokerGrpcService {
-    pub fn new(message_broker: MessageBroker) -> Self {
-        Self { message_broker }
+    pub fn new(
+        message_broker: MessageBroker,
+        authenticator: Option<AgentAuthenticator>,
+        check_store: Option<CheckStore>,
+    ) -> Self {
+        Self {
+            message_broker,
+            authenticator,
+            check_store,
+        }
     }
 
-    pub async fn run_on(self, addr: std::net::SocketAddr) -> Result<(), ApiError> {
-        tracing::info!("Starting API server on {}", addr);
-        let server = BrokerServer::new(self);
-        Server::builder()
-            .add_service(server)
-            .serve(addr)
+    pub async fn run_on(self, config: ApiConfig) -> Result<(), ApiError> {
+        let mut server = Server::builder();
+        if let Some(tls) = &config.tls {
+            server = server
+                .tls_config(Self::server_tls_config(tls).await?)
+                .map_err(|e| ApiError::InvalidTlsConfiguration(e.to_string()))?;
+            tracing::info!(mutual_tls = tls.client_ca.is_some(), "TLS is enabled");
+        }
+
+        tracing::info!("Starting API server on {}", config.listen_address);
+        server
+            .add_service(BrokerServer::new(self))
+            .serve(config.listen_address)
             .await
             .map_err(ApiError::ServerFailure)
     }
+
+    async fn server_tls_config(config: &ApiTlsConfig) -> Result<ServerTlsConfig, ApiError> {
+        let identity =
+            Identity::from_pem(read_pem(&config.cert).await?, read_pem(&config.key).await?);
+        let mut tls = ServerTlsConfig::new().identity(identity);
+        if let Some(client_ca) = &config.client_ca {
+            tls = tls.client_ca_root(Certificate::from_pem(read_pem(client_ca).await?));
+        }
+        Ok(tls)
+    }
+}
+
+async fn read_pem(path: &Path) -> Result<Vec<u8>, ApiError> {
+    tokio::fs::read(path).await.map_err(|e| {
+        ApiError::InvalidTlsConfiguration(format!("Unable to read {}: {}", path.display(), e))
+    })
 }
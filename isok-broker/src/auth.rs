@@ -0,0 +1,224 @@
+use crate::config::{AuthConfig, Error};
+use biscuit_auth::error::Token;
+use biscuit_auth::macros::fact;
+use biscuit_auth::{Authorizer, Biscuit, PublicKey};
+use isok_data::broker_rpc::Tags;
+
+/// Verify the biscuit tokens agents send along their calls.
+///
+/// Tokens are generated with `isok-cli new-agent` and must carry a `service` fact,
+/// their own checks (such as an expiration date) are evaluated as well. A token may
+/// restrict the `agent_id`, `zone` and `region` an agent reports for, in which case
+/// they must match the tags of the batch or of the requested checks.
+#[derive(Debug, thiserror::Error)]
+pub enum AuthError {
+    #[error("Missing agent token")]
+    MissingToken,
+    #[error("Invalid agent token")]
+    InvalidToken,
+    #[error("Agent token isn't authorized for this call")]
+    Unauthorized,
+    #[error("Unable to evaluate agent token: {0}")]
+    Evaluation(String),
+}
+
+impl From<AuthError> for tonic::Status {
+    fn from(value: AuthError) -> Self {
+        match value {
+            AuthError::MissingToken | AuthError::InvalidToken => {
+                tonic::Status::unauthenticated(value.to_string())
+            }
+            AuthError::Unauthorized => tonic::Status::permission_denied(value.to_string()),
+            AuthError::Evaluation(_) => tonic::Status::internal(value.to_string()),
+        }
+    }
+}
+
+pub struct AgentAuthenticator {
+    root_public_key: PublicKey,
+    services: Vec<String>,
+}
+
+impl AgentAuthenticator {
+    const AUTHORIZATION_METADATA: &'static str = "authorization";
+    const BEARER_PREFIX: &'static str = "Bearer ";
+
+    pub fn new(config: &AuthConfig) -> Result<Self, Error> {
+        let root_public_key = PublicKey::from_bytes_hex(config.root_public_key.trim())
+            .map_err(|e| Error::InvalidRootPublicKey(e.to_string()))?;
+        Ok(Self {
+            root_public_key,
+            services: config.services.clone(),
+        })
+    }
+
+    /// Authorize a call, `tags` being the tags the call carries, e.g. the ones of a batch
+    pub fn authorize<T>(
+        &self,
+        request: &tonic::Request<T>,
+        tags: Option<&Tags>,
+    ) -> Result<(), AuthError> {
+        let token = request
+            .metadata()
+            .get(Self::AUTHORIZATION_METADATA)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix(Self::BEARER_PREFIX))
+            .ok_or(AuthError::MissingToken)?;
+        let biscuit = Biscuit::from_base64(token.trim(), self.root_public_key).map_err(|e| {
+            tracing::debug!("Rejected agent token: {}", e);
+            AuthError::InvalidToken
+        })?;
+
+        let mut authorizer = self
+            .authorizer(&biscuit, tags)
+            .map_err(|e| AuthError::Evaluation(e.to_string()))?;
+        authorizer.authorize().map(|_| ()).map_err(|e| {
+            tracing::debug!("Agent token isn't authorized: {:?}", e);
+            AuthError::Unauthorized
+        })
+    }
+
+    fn authorizer(&self, biscuit: &Biscuit, tags: Option<&Tags>) -> Result<Authorizer, Token> {
+        let mut authorizer = biscuit.authorizer()?;
+        authorizer.set_time();
+
+        if let Some(tags) = tags {
+            authorizer.add_fact(fact!(
+                "batch_tags({agent_id}, {zone}, {region})",
+                agent_id = tags.agent_id.clone(),
+                zone = tags.zone.clone(),
+                region = tags.region.clone()
+            ))?;
+        }
+        // Claims restricting the tags the agent reports for are optional,
+        // but must match the batch when present
+        authorizer.add_code(
+            r#"
+            deny if agent_id($claim), batch_tags($agent_id, $zone, $region), $claim != $agent_id;
+            deny if zone($claim), batch_tags($agent_id, $zone, $region), $claim != $zone;
+            deny if region($claim), batch_tags($agent_id, $zone, $region), $claim != $region;
+            "#,
+        )?;
+
+        if self.services.is_empty() {
+            authorizer.add_code("allow if service($service);")?;
+        } else {
+            for service in &self.services {
+                authorizer.add_fact(fact!(
+                    "allowed_service({service})",
+                    service = service.clone()
+                ))?;
+            }
+            authorizer.add_code("allow if service($service), allowed_service($service);")?;
+        }
+        Ok(authorizer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use biscuit_auth::builder_ext::BuilderExt;
+    use biscuit_auth::KeyPair;
+    use pretty_assertions::assert_eq;
+    use std::time::{Duration, SystemTime};
+
+    fn authenticator(root: &KeyPair, services: Vec<&str>) -> AgentAuthenticator {
+        AgentAuthenticator::new(&AuthConfig {
+            root_public_key: root.public().to_bytes_hex(),
+            services: services.into_iter().map(String::from).collect(),
+        })
+        .expect("Expected a valid public key")
+    }
+
+    fn token(root: &KeyPair, code: &str, expires_at: Option<SystemTime>) -> String {
+        let mut builder = Biscuit::builder();
+        builder.add_code(code).unwrap();
+        if let Some(expires_at) = expires_at {
+            builder.check_expiration_date(expires_at);
+        }
+        builder.build(root).unwrap().to_base64().unwrap()
+    }
+
+    fn request(token: Option<&str>) -> tonic::Request<()> {
+        let mut request = tonic::Request::new(());
+        if let Some(token) = token {
+            request.metadata_mut().insert(
+                "authorization",
+                format!("Bearer {}", token).try_into().unwrap(),
+            );
+        }
+        request
+    }
+
+    fn tags() -> Tags {
+        Tags {
+            agent_id: "agent-1".to_string(),
+            zone: "dev".to_string(),
+            region: "eu-west".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_valid_token() {
+        let root = KeyPair::new();
+        let authenticator = authenticator(&root, vec!["payments"]);
+        let token = token(
+            &root,
+            r#"service("payments"); agent_id("agent-1"); zone("dev");"#,
+            Some(SystemTime::now() + Duration::from_secs(3600)),
+        );
+        assert!(authenticator
+            .authorize(&request(Some(&token)), Some(&tags()))
+            .is_ok());
+        // Calls that don't carry a batch only require a valid token
+        assert!(authenticator
+            .authorize(&request(Some(&token)), None)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_unauthenticated() {
+        let root = KeyPair::new();
+        let authenticator = authenticator(&root, vec![]);
+        let foreign_token = token(&KeyPair::new(), r#"service("payments");"#, None);
+        for request in [
+            request(None),
+            request(Some("not a biscuit")),
+            request(Some(&foreign_token)),
+        ] {
+            let status: tonic::Status = authenticator
+                .authorize(&request, Some(&tags()))
+                .unwrap_err()
+                .into();
+            assert_eq!(status.code(), tonic::Code::Unauthenticated);
+        }
+    }
+
+    #[test]
+    fn test_permission_denied() {
+        let root = KeyPair::new();
+        let authenticator = authenticator(&root, vec!["payments"]);
+        for token in [
+            // Expired
+            token(
+                &root,
+                r#"service("payments");"#,
+                Some(SystemTime::now() - Duration::from_secs(3600)),
+            ),
+            // Service not allowed by the broker
+            token(&root, r#"service("billing");"#, None),
+            // No service at all
+            token(&root, r#"agent_id("agent-1");"#, None),
+            // Claims that don't match the batch tags
+            token(&root, r#"service("payments"); agent_id("agent-2");"#, None),
+            token(&root, r#"service("payments"); region("us-east");"#, None),
+        ] {
+            let status: tonic::Status = authenticator
+                .authorize(&request(Some(&token)), Some(&tags()))
+                .unwrap_err()
+                .into();
+            assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        }
+    }
+}
@@ -0,0 +1,228 @@
+use crate::config::ChecksConfig;
+use isok_data::broker_rpc::Tags;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+#[derive(Debug, thiserror::Error)]
+pub enum CheckStoreError {
+    #[error("Unable to read checks file {path}: {source}")]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Invalid checks file {path}: {reason}")]
+    Invalid { path: PathBuf, reason: String },
+}
+
+#[derive(Debug, Deserialize)]
+struct ChecksFile {
+    check_sets: Vec<CheckSet>,
+}
+
+/// Checks assigned to the agents matching every non-empty selector
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CheckSet {
+    #[serde(default)]
+    zones: Vec<String>,
+    #[serde(default)]
+    regions: Vec<String>,
+    #[serde(default)]
+    agent_ids: Vec<String>,
+    checks: Vec<Value>,
+}
+
+impl CheckSet {
+    fn selects(&self, tags: &Tags) -> bool {
+        let matches =
+            |selector: &[String], tag: &String| selector.is_empty() || selector.contains(tag);
+        matches(&self.zones, &tags.zone)
+            && matches(&self.regions, &tags.region)
+            && matches(&self.agent_ids, &tags.agent_id)
+    }
+}
+
+/// Checks of an agent, along with their version
+#[derive(Debug, PartialEq)]
+pub(crate) struct AgentChecks {
+    pub(crate) version: String,
+    /// Check definitions serialized to JSON
+    pub(crate) checks: Vec<String>,
+}
+
+/// Check definitions served to agents, read from a YAML file.
+///
+/// The broker doesn't interpret definitions beyond their `id`, which is required so
+/// every agent reports a check under the same ID. Agents validate the definitions
+/// when loading them.
+pub(crate) struct CheckStore {
+    path: PathBuf,
+}
+
+impl CheckStore {
+    /// Open the checks file, which must be valid for the broker to start
+    pub(crate) async fn open(config: &ChecksConfig) -> Result<Self, CheckStoreError> {
+        let store = CheckStore {
+            path: config.path.clone(),
+        };
+        store.read().await?;
+        Ok(store)
+    }
+
+    /// Checks assigned to the agent with `tags`. The file is read on every call,
+    /// so changes are served without restarting the broker.
+    pub(crate) async fn checks_for(&self, tags: &Tags) -> Result<AgentChecks, CheckStoreError> {
+        let checks = self
+            .read()
+            .await?
+            .iter()
+            .filter(|set| set.selects(tags))
+            .flat_map(|set| &set.checks)
+            .map(Value::to_string)
+            .collect::<Vec<_>>();
+
+        let mut hasher = Sha256::new();
+        for check in &checks {
+            hasher.update(check.as_bytes());
+            hasher.update(b"\n");
+        }
+        Ok(AgentChecks {
+            version: format!("{:x}", hasher.finalize()),
+            checks,
+        })
+    }
+
+    async fn read(&self) -> Result<Vec<CheckSet>, CheckStoreError> {
+        let content = tokio::fs::read_to_string(&self.path)
+            .await
+            .map_err(|source| CheckStoreError::Read {
+                path: self.path.clone(),
+                source,
+            })?;
+        let file: ChecksFile =
+            serde_yaml::from_str(&content).map_err(|e| self.invalid(e.to_string()))?;
+
+        let mut ids = HashSet::new();
+        for check in file.check_sets.iter().flat_map(|set| &set.checks) {
+            let id = check
+                .get("id")
+                .and_then(Value::as_str)
+                .ok_or_else(|| self.invalid("Every check must have an `id`"))?;
+            if !ids.insert(id) {
+                return Err(self.invalid(format!("Check {} is defined twice", id)));
+            }
+        }
+        Ok(file.check_sets)
+    }
+
+    fn invalid(&self, reason: impl Into<String>) -> CheckStoreError {
+        CheckStoreError::Invalid {
+            path: self.path.clone(),
+            reason: reason.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    async fn store(content: &str) -> (tempfile::TempDir, Result<CheckStore, CheckStoreError>) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("checks.yaml");
+        std::fs::write(&path, content).unwrap();
+        let store = CheckStore::open(&ChecksConfig { path }).await;
+        (dir, store)
+    }
+
+    fn tags(agent_id: &str, zone: &str, region: &str) -> Tags {
+        Tags {
+            agent_id: agent_id.to_string(),
+            zone: zone.to_string(),
+            region: region.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_checks_selected_by_tags() {
+        let (_dir, store) = store(
+            r#"
+            check_sets:
+              - checks:
+                  - { id: "everywhere", type: "tcp" }
+              - zones: ["prod"]
+                regions: ["eu-west", "us-east"]
+                checks:
+                  - { id: "prod", type: "tcp" }
+              - agent_ids: ["agent-1"]
+                checks:
+                  - { id: "agent-1", type: "tcp" }
+            "#,
+        )
+        .await;
+        let store = store.unwrap();
+
+        let ids = |checks: AgentChecks| {
+            checks
+                .checks
+                .iter()
+                .map(|check| serde_json::from_str::<Value>(check).unwrap()["id"].clone())
+                .collect::<Vec<_>>()
+        };
+        let checks = store
+            .checks_for(&tags("agent-1", "prod", "eu-west"))
+            .await
+            .unwrap();
+        assert_eq!(ids(checks), ["everywhere", "prod", "agent-1"]);
+        let checks = store
+            .checks_for(&tags("agent-2", "prod", "ap-south"))
+            .await
+            .unwrap();
+        assert_eq!(ids(checks), ["everywhere"]);
+    }
+
+    #[tokio::test]
+    async fn test_version_changes_with_checks() {
+        let (_dir, store) = store(
+            r#"
+            check_sets:
+              - checks:
+                  - { id: "everywhere", type: "tcp" }
+              - zones: ["prod"]
+                checks:
+                  - { id: "prod", type: "tcp" }
+            "#,
+        )
+        .await;
+        let store = store.unwrap();
+
+        let version = |tags: Tags| {
+            let store = &store;
+            async move { store.checks_for(&tags).await.unwrap().version }
+        };
+        let dev = version(tags("agent-1", "dev", "eu-west")).await;
+        assert_eq!(dev, version(tags("agent-2", "dev", "us-east")).await);
+        assert_ne!(dev, version(tags("agent-1", "prod", "eu-west")).await);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_checks_file() {
+        for content in [
+            "check_sets: [{ checks: [{ type: \"tcp\" }] }]",
+            "check_sets: [{ checks: [{ id: \"a\" }] }, { checks: [{ id: \"a\" }] }]",
+            "check_sets: [{ zone: [\"prod\"], checks: [] }]",
+        ] {
+            let (_dir, store) = store(content).await;
+            assert!(
+                matches!(store, Err(CheckStoreError::Invalid { .. })),
+                "Expected {} to be rejected",
+                content
+            );
+        }
+    }
+}
@@ -1,4 +1,5 @@
 use crate::api::ApiError;
+use crate::checks::CheckStoreError;
 use crate::message_broker::MessageBrokerError;
 use figment::providers::{Format, Yaml};
 use figment::Figment;
@@ -11,6 +12,12 @@ use std::path::PathBuf;
 pub struct Config {
     pub kafka: KafkaConfig,
     pub api: ApiConfig,
+    /// Authentication of agents, every call is accepted when not set
+    #[serde(default)]
+    pub auth: Option<AuthConfig>,
+    /// Checks served to agents pulling their configuration from the broker
+    #[serde(default)]
+    pub checks: Option<ChecksConfig>,
 }
 
 #[derive(Deserialize, Clone)]
@@ -22,16 +29,56 @@ pub struct KafkaConfig {
 #[derive(Deserialize, Clone)]
 pub struct ApiConfig {
     pub listen_address: SocketAddr,
+    /// Serve the API over TLS, plaintext is served when not set
+    #[serde(default)]
+    pub tls: Option<ApiTlsConfig>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ApiTlsConfig {
+    /// PEM certificate chain of the broker
+    pub cert: PathBuf,
+    /// PEM private key of the broker certificate
+    pub key: PathBuf,
+    /// PEM bundle of the certificate authorities signing agents certificates. When set,
+    /// agents must present a certificate signed by one of them (mutual TLS).
+    #[serde(default)]
+    pub client_ca: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct AuthConfig {
+    /// Hex-encoded public key of the broker keypair, generated with `isok-cli new-broker`
+    pub root_public_key: String,
+    /// Services agents are allowed to report for, any service is accepted when empty
+    #[serde(default)]
+    pub services: Vec<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct ChecksConfig {
+    /// YAML file of the check sets, assigned to agents by their tags
+    pub path: PathBuf,
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("Unable to load config file: {0}")]
-    UnableToLoadConfigFile(#[from] figment::Error),
+    UnableToLoadConfigFile(#[source] Box<figment::Error>),
     #[error("Unable to create message broker: {0}")]
     UnableToCreateMessageBroker(#[from] MessageBrokerError),
     #[error("Unable to start API server")]
     UnableToStartApiServer(#[from] ApiError),
+    #[error("Invalid root public key: {0}")]
+    InvalidRootPublicKey(String),
+    #[error("Unable to load the checks served to agents: {0}")]
+    UnableToLoadChecks(#[from] CheckStoreError),
+}
+
+impl From<figment::Error> for Error {
+    fn from(value: figment::Error) -> Self {
+        Error::UnableToLoadConfigFile(Box::new(value))
+    }
 }
 
 impl Config {
@@ -52,7 +99,10 @@ impl Default for Config {
             },
             api: ApiConfig {
                 listen_address: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 9000),
+                tls: None,
             },
+            auth: None,
+            checks: None,
         }
     }
 }
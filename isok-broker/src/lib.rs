@@ -1,16 +1,35 @@
 mod api;
+pub mod auth;
+mod checks;
 pub mod config;
 mod message_broker;
 
+use crate::auth::AgentAuthenticator;
+use crate::checks::CheckStore;
 use crate::config::{Config, Error};
 use crate::message_broker::{KafkaMessageBroker, MessageBroker};
 
 pub async fn run(config: Config) -> Result<(), Error> {
+    let authenticator = match &config.auth {
+        Some(auth) => Some(AgentAuthenticator::new(auth)?),
+        None => {
+            tracing::warn!("Authentication is disabled, any agent is able to send results");
+            None
+        }
+    };
+    let check_store = match &config.checks {
+        Some(checks) => Some(CheckStore::open(checks).await?),
+        None => None,
+    };
     let message_broker = KafkaMessageBroker::try_new(config.kafka)?;
 
-    api::BrokerGrpcService::new(MessageBroker::Kafka(message_broker))
-        .run_on(config.api.listen_address)
-        .await
-        .map_err(Error::UnableToStartApiServer)?;
+    api::BrokerGrpcService::new(
+        MessageBroker::Kafka(message_broker),
+        authenticator,
+        check_store,
+    )
+    .run_on(config.api)
+    .await
+    .map_err(Error::UnableToStartApiServer)?;
     Ok(())
 }
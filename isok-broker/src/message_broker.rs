@@ -71,7 +71,7 @@ impl TryFrom<KafkaConfig> for FutureProducer {
     fn try_from(value: KafkaConfig) -> Result<Self, Self::Error> {
         ClientConfig::from_iter(value.properties)
             .create()
-            .map_err(|e| MessageBrokerError::UnableToCreateProducer(e))
+            .map_err(MessageBrokerError::UnableToCreateProducer)
     }
 }
 
@@ -111,6 +111,8 @@ mod tests {
             status: CheckJobStatus::Reachable.into(),
             metrics: Default::default(),
             tags: None,
+            reason: None,
+            received_at: None,
             details: Default::default(),
         }];
 
@@ -1,6 +1,10 @@
 use std::{io, time::SystemTime};
 
-use biscuit_auth::{builder_ext::BuilderExt, Biscuit, KeyPair, PrivateKey};
+use biscuit_auth::{
+    builder::{fact, string},
+    builder_ext::BuilderExt,
+    Biscuit, KeyPair, PrivateKey,
+};
 use chrono::DateTime;
 
 use super::private_key;
@@ -34,6 +38,18 @@ pub struct NewAgentCommand {
     #[clap(long, value_parser = parse_service)]
     pub service: Box<str>,
 
+    /// Restrict the token to the agent reporting with this `agent_id`.
+    #[clap(long)]
+    pub agent_id: Option<String>,
+
+    /// Restrict the token to agents reporting for this zone.
+    #[clap(long)]
+    pub zone: Option<String>,
+
+    /// Restrict the token to agents reporting for this region.
+    #[clap(long)]
+    pub region: Option<String>,
+
     /// Output the raw bytes of the token instead of the base64-encoded string.
     #[clap(long)]
     pub raw: bool,
@@ -56,6 +72,22 @@ impl NewAgentCommand {
             ));
         }
 
+        for (name, value) in [
+            ("agent_id", &self.agent_id),
+            ("zone", &self.zone),
+            ("region", &self.region),
+        ] {
+            let Some(value) = value else {
+                continue;
+            };
+            if let Err(error) = builder.add_fact(fact(name, &[string(value)])) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("failed to add {name} claim: {error}"),
+                ));
+            }
+        }
+
         if let Some(ttl) = self.ttl {
             builder.check_expiration_date(ttl);
         }
@@ -89,3 +121,96 @@ impl NewAgentCommand {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use isok_broker::auth::AgentAuthenticator;
+    use isok_broker::config::AuthConfig;
+    use isok_data::broker_rpc::Tags;
+
+    fn generate_token(root: &KeyPair, claims: &[&str]) -> String {
+        let private_key = root.private().to_bytes_hex();
+        let args = [
+            &[
+                "new-agent",
+                "--private-key",
+                &private_key,
+                "--service",
+                "payments",
+            ],
+            claims,
+        ]
+        .concat();
+        let mut token = vec![];
+        NewAgentCommand::try_parse_from(args)
+            .unwrap()
+            .run(&mut token)
+            .unwrap();
+        String::from_utf8(token).unwrap()
+    }
+
+    fn request(token: &str) -> tonic::Request<()> {
+        let mut request = tonic::Request::new(());
+        request.metadata_mut().insert(
+            "authorization",
+            format!("Bearer {}", token).try_into().unwrap(),
+        );
+        request
+    }
+
+    fn tags(agent_id: &str, zone: &str, region: &str) -> Tags {
+        Tags {
+            agent_id: agent_id.to_string(),
+            zone: zone.to_string(),
+            region: region.to_string(),
+        }
+    }
+
+    // Tokens restricted to an agent, zone and region are only accepted by the
+    // broker for batches carrying the same tags
+    #[test]
+    fn test_claims_authorized_by_broker() {
+        let root = KeyPair::new();
+        let authenticator = AgentAuthenticator::new(&AuthConfig {
+            root_public_key: root.public().to_bytes_hex(),
+            services: vec!["payments".to_string()],
+        })
+        .unwrap();
+        let token = generate_token(
+            &root,
+            &[
+                "--agent-id",
+                "agent-1",
+                "--zone",
+                "dev",
+                "--region",
+                "eu-west",
+            ],
+        );
+
+        assert!(authenticator
+            .authorize(&request(&token), Some(&tags("agent-1", "dev", "eu-west")))
+            .is_ok());
+        for tags in [
+            tags("agent-2", "dev", "eu-west"),
+            tags("agent-1", "prod", "eu-west"),
+            tags("agent-1", "dev", "us-east"),
+        ] {
+            assert!(
+                authenticator
+                    .authorize(&request(&token), Some(&tags))
+                    .is_err(),
+                "Expected {:?} to be rejected",
+                tags
+            );
+        }
+
+        // Without claims, the token is accepted for any tags
+        let token = generate_token(&root, &[]);
+        assert!(authenticator
+            .authorize(&request(&token), Some(&tags("agent-2", "prod", "us-east")))
+            .is_ok());
+    }
+}